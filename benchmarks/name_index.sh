@@ -0,0 +1,30 @@
+#!/usr/bin/env bash
+# Compare searches resolved with the name index to full cache scans.
+#
+# Generates a cache with 120 000 packages, about the size of nixpkgs, in a
+# temporary folder. It's written in the legacy format without header, so
+# `nps` migrates it to its current format and builds the name index on the
+# first search. Adding `--any` to a single search term doesn't change the
+# matches, but makes `nps` scan the whole cache like `get_matches` does.
+#
+# Usage: ./benchmarks/name_index.sh [PATH_TO_NPS]
+set -euo pipefail
+
+nps="$(realpath "${1:-./target/release/nps}")"
+cache_folder="$(mktemp -d)"
+trap 'rm -rf "$cache_folder"' EXIT
+
+for i in $(seq 0 119999); do
+    printf 'package-%06d %d.%d.%d Description of package number %d\n' \
+        "$i" $((i % 7)) $((i % 13)) $((i % 29)) "$i"
+done | sort > "$cache_folder/nps.experimental.cache"
+
+# Migrate the cache and build the index outside of the measurements
+"$nps" --cache-folder="$cache_folder" -e=true 'name:^package-000000$' > /dev/null
+test -f "$cache_folder/nps.experimental.index"
+
+hyperfine --warmup 10 --shell=none \
+    "$nps --cache-folder=$cache_folder -e=true name:^package-054321\$" \
+    "$nps --cache-folder=$cache_folder -e=true --any name:^package-054321\$" \
+    "$nps --cache-folder=$cache_folder -e=true name:^package-0543" \
+    "$nps --cache-folder=$cache_folder -e=true --any name:^package-0543"
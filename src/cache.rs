@@ -0,0 +1,1639 @@
+//! Package caches: refreshing, reading, indexing and comparing them
+
+use memmap2::Mmap;
+use rustix::fs::{flock, FlockOperation};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs,
+    io::{self, BufRead, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+
+/// Version of the cache file format.
+///
+/// Bump this on incompatible changes, caches with a different format are rebuilt.
+pub const CACHE_FORMAT: u32 = 3;
+
+/// Cache file of channels
+const CACHE_FILE: &str = "nps.cache";
+
+/// Cache file of `DEFAULT_FLAKE`
+const EXPERIMENTAL_CACHE_FILE: &str = "nps.experimental.cache";
+
+/// Flake searched if no other flake is requested
+const DEFAULT_FLAKE: &str = "nixpkgs";
+
+/// First bytes of a name index file, followed by its format version.
+const INDEX_MAGIC: &[u8; 8] = b"NPSINDEX";
+
+/// Version of the name index format.
+const INDEX_FORMAT: u32 = 2;
+
+/// Interval of progress reports while refreshing
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Poll interval while waiting for another refresh of the same cache
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Format to parse JSON package info of `nix search` into
+#[derive(Debug, Deserialize)]
+struct Package {
+    pname: String,
+    version: String,
+    description: String,
+}
+
+/// Format to parse JSON package info of `nix-env -qaP --json --meta` into
+#[derive(Debug, Deserialize)]
+struct NixEnvPackage {
+    pname: Option<String>,
+    version: String,
+    #[serde(default)]
+    meta: NixMeta,
+}
+
+/// Format to parse the `meta` attribute of packages into
+///
+/// Licenses, homepages, platforms and maintainers come in many shapes, they
+/// are normalized by `PackageMeta::new`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NixMeta {
+    description: Option<String>,
+    main_program: Option<String>,
+    license: Option<serde_json::Value>,
+    homepage: Option<serde_json::Value>,
+    platforms: Option<serde_json::Value>,
+    maintainers: Option<serde_json::Value>,
+    broken: Option<bool>,
+    unfree: Option<bool>,
+    insecure: Option<bool>,
+}
+
+/// Package metadata, stored next to the cache file
+///
+/// It is kept out of the cache file, so searches don't match it.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct PackageMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pname: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub main_programs: Vec<String>,
+    /// SPDX identifiers, or names of licenses without one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub licenses: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub homepages: Vec<String>,
+    /// Platforms given by name, e.g. `x86_64-linux`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintainers: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub broken: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unfree: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub insecure: bool,
+    /// Whether `broken`, `unfree` and `insecure` were evaluated, they are
+    /// `false` otherwise, e.g. for flake packages `nps` can't get `meta` of
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub evaluated: bool,
+}
+
+impl PackageMeta {
+    fn new(pname: Option<String>, meta: NixMeta) -> PackageMeta {
+        let licenses = meta_values(&meta.license)
+            .filter_map(|license| match license {
+                serde_json::Value::String(license) => Some(license.to_string()),
+                serde_json::Value::Object(license) => ["spdxId", "shortName", "fullName"]
+                    .iter()
+                    .find_map(|key| license.get(*key)?.as_str())
+                    .map(str::to_string),
+                _ => None,
+            })
+            .collect();
+        let maintainers = meta_values(&meta.maintainers)
+            .filter_map(|maintainer| {
+                let name = maintainer.get("name")?.as_str()?;
+                Some(
+                    match maintainer.get("github").and_then(|github| github.as_str()) {
+                        Some(github) => format!("{name} (@{github})"),
+                        None => name.to_string(),
+                    },
+                )
+            })
+            .collect();
+        let strings = |value| {
+            meta_values(value)
+                .filter_map(|value| value.as_str().map(str::to_string))
+                .collect()
+        };
+
+        PackageMeta {
+            pname,
+            main_programs: meta.main_program.into_iter().collect(),
+            licenses,
+            homepages: strings(&meta.homepage),
+            platforms: strings(&meta.platforms),
+            maintainers,
+            broken: meta.broken.unwrap_or(false),
+            unfree: meta.unfree.unwrap_or(false),
+            insecure: meta.insecure.unwrap_or(false),
+            evaluated: true,
+        }
+    }
+}
+
+/// Values of a `meta` attribute that is either a single value or a list
+fn meta_values(value: &Option<serde_json::Value>) -> impl Iterator<Item = &serde_json::Value> {
+    match value {
+        Some(serde_json::Value::Array(values)) => values.iter().collect::<Vec<_>>(),
+        Some(value) => vec![value],
+        None => vec![],
+    }
+    .into_iter()
+}
+
+/// Package info parsed from `nix search` or `nix-env` output
+#[derive(Debug, Default)]
+struct ParsedPackages {
+    /// Cache lines (NAME\tVERSION\tDESCRIPTION)
+    lines: String,
+    /// Metadata by package name
+    meta: HashMap<String, PackageMeta>,
+    /// Attribute sets of flake packages, e.g. `legacyPackages.x86_64-linux`
+    attribute_sets: Vec<String>,
+}
+
+/// Sorted table of package names, stored next to the cache file
+///
+/// The index file holds `INDEX_MAGIC`, `INDEX_FORMAT`, the number of packages,
+/// the length of the cache body and the creation time of the cache, followed
+/// by the start and length of every line in the body. Lines are sorted by
+/// lowercase package name, so exact names and prefixes can be looked up
+/// without scanning the cache. All numbers are little-endian `u32`s, except
+/// the `u64` body length and creation time.
+pub(crate) struct NameIndex {
+    mmap: Mmap,
+}
+
+/// Where the cached package info comes from
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheSource {
+    Channels,
+    Flake,
+}
+
+/// Metadata header, stored as JSON in the first line of a cache file
+///
+/// The remaining lines contain one package each, with the tab-separated
+/// columns NAME, VERSION and DESCRIPTION.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CacheHeader {
+    pub format: u32,
+    pub source: CacheSource,
+    pub flake: Option<String>,
+    pub revision: Option<String>,
+    pub created: u64,
+    pub nps_version: String,
+}
+
+/// Package added, removed or updated between two caches
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ChangedPackage<'a> {
+    pub name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new_version: Option<&'a str>,
+}
+
+/// Differences between the previous and the current cache of a source
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CacheChanges<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flake: Option<&'a str>,
+    pub previous_created: u64,
+    pub created: u64,
+    pub added: Vec<ChangedPackage<'a>>,
+    pub removed: Vec<ChangedPackage<'a>>,
+    pub updated: Vec<ChangedPackage<'a>>,
+}
+
+/// Progress of a refresh, reported to the caller of `refresh`
+#[derive(Debug, PartialEq)]
+pub enum RefreshProgress<'a> {
+    /// A message for the user, e.g. that the refresh started
+    Message(&'a str),
+    /// The `nix` command is running since `elapsed`, evaluating `attribute`
+    /// if it reported one yet
+    Running {
+        elapsed: Duration,
+        attribute: &'a str,
+    },
+    /// The `nix` command finished or was killed
+    Stopped,
+}
+
+/// Path of the cache file for channels (`None`) or a flake
+///
+/// The default flake keeps its historic file name. Other flake references are
+/// percent-encoded, so every reference gets a unique and valid file name.
+pub(crate) fn cache_file_path(cache_folder: &Path, flake: Option<&str>) -> PathBuf {
+    match flake {
+        None => cache_folder.join(CACHE_FILE),
+        Some(flake) if flake == DEFAULT_FLAKE => cache_folder.join(EXPERIMENTAL_CACHE_FILE),
+        Some(flake) => {
+            let mut encoded = String::new();
+            for byte in flake.bytes() {
+                match byte {
+                    b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                        encoded.push(byte as char)
+                    }
+                    _ => encoded.push_str(&format!("%{byte:02X}")),
+                }
+            }
+            cache_folder.join(format!("nps.flake.{encoded}.cache"))
+        }
+    }
+}
+
+/// Path of the cache file replaced by the last refresh
+pub(crate) fn previous_cache_file_path(cache_file: &Path) -> PathBuf {
+    cache_file.with_extension("cache.previous")
+}
+
+/// Path of the name index file that belongs to a cache file
+fn index_file_path(cache_file: &Path) -> PathBuf {
+    cache_file.with_extension("index")
+}
+
+/// Path of the package metadata file that belongs to a cache file
+fn meta_file_path(cache_file: &Path) -> PathBuf {
+    cache_file.with_extension("meta")
+}
+
+/// Path of the lock file that guards refreshes of a cache file
+fn lock_file_path(cache_file: &Path) -> PathBuf {
+    cache_file.with_extension("lock")
+}
+
+/// Creation time of a cache file, `None` if it's missing or unusable
+pub(crate) fn cache_created(file_path: &Path) -> io::Result<Option<u64>> {
+    let file = match fs::File::open(file_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    // The header is all we need, don't read the whole cache
+    let mut first_line = String::new();
+    io::BufReader::new(file).read_line(&mut first_line)?;
+    match parse_cache(&first_line) {
+        Ok((Some(header), _body)) => Ok(Some(header.created)),
+        // Legacy caches only know their modification time
+        Ok((None, _body)) => Ok(fs::metadata(file_path)?
+            .modified()
+            .ok()
+            .and_then(|modified| unix_timestamp(modified).ok())),
+        Err(err) => {
+            log::debug!("Can't use cache {}: {err}", file_path.display());
+            Ok(None)
+        }
+    }
+}
+
+/// Versions of all packages in the body of a cache, sorted by name
+fn cache_versions(body: &str) -> BTreeMap<&str, &str> {
+    body.lines()
+        .filter_map(|line| {
+            let mut split_line = line.splitn(3, '\t');
+            Some((split_line.next()?, split_line.next().unwrap_or("")))
+        })
+        .collect()
+}
+
+/// Compare the bodies of two caches
+pub(crate) fn cache_changes<'a>(previous_body: &'a str, body: &'a str) -> CacheChanges<'a> {
+    let previous_versions = cache_versions(previous_body);
+    let versions = cache_versions(body);
+
+    let mut changes = CacheChanges::default();
+    for (name, version) in versions.iter() {
+        match previous_versions.get(name) {
+            None => changes.added.push(ChangedPackage {
+                name,
+                old_version: None,
+                new_version: Some(version),
+            }),
+            Some(previous_version) if previous_version != version => {
+                changes.updated.push(ChangedPackage {
+                    name,
+                    old_version: Some(previous_version),
+                    new_version: Some(version),
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, previous_version) in previous_versions.iter() {
+        if !versions.contains_key(name) {
+            changes.removed.push(ChangedPackage {
+                name,
+                old_version: Some(previous_version),
+                new_version: None,
+            });
+        }
+    }
+    changes
+}
+
+/// Header for a legacy cache file of channels (`None`) or a flake
+///
+/// Legacy caches carry no metadata, use what we can still find out.
+fn legacy_header(flake: Option<&str>, file_path: &Path) -> Result<CacheHeader, Box<dyn Error>> {
+    let created = fs::metadata(file_path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|err| format!("Can't get cache modification time: {err}"))?;
+    Ok(CacheHeader {
+        format: CACHE_FORMAT,
+        source: match flake {
+            Some(_) => CacheSource::Flake,
+            None => CacheSource::Channels,
+        },
+        flake: flake.map(|flake| flake.to_string()),
+        revision: None,
+        created: unix_timestamp(created)?,
+        nps_version: env!("CARGO_PKG_VERSION").to_string(),
+    })
+}
+
+/// Read the header and package lines of a cache file of any format
+///
+/// Package lines look the same in all formats with header, so caches of older
+/// formats are read too, e.g. the previous cache after an update of `nps`.
+pub(crate) fn read_any_cache(
+    flake: Option<&str>,
+    file_path: &Path,
+) -> Result<(CacheHeader, String), Box<dyn Error>> {
+    let content = fs::read_to_string(file_path)
+        .map_err(|err| format!("Can't open file {}: {err}", file_path.display()))?;
+    match split_cache(&content)? {
+        (Some(header), body) => Ok((header, body.to_string())),
+        (None, legacy_body) => Ok((
+            legacy_header(flake, file_path)?,
+            parse_legacy_cache_to_lines(legacy_body),
+        )),
+    }
+}
+
+/// Assemble a cache line from its columns
+///
+/// Tabs and newlines would break the column layout, replace them with spaces.
+fn cache_line(name: &str, version: &str, description: &str) -> String {
+    let sanitize = |column: &str| column.replace(['\t', '\n', '\r'], " ");
+    format!(
+        "{}\t{}\t{}",
+        sanitize(name),
+        sanitize(version),
+        sanitize(description)
+    )
+}
+
+/// Parse package info from `nix search` JSON to (NAME\tVERSION\tDESCRIPTION) lines
+fn parse_json_to_lines(raw_output: &str) -> Result<ParsedPackages, Box<dyn Error>> {
+    // Load JSON package info into a HashMap
+    let parsed: HashMap<String, Package> =
+        serde_json::from_str(raw_output).map_err(|err| format!("Can't parse JSON: {err}"))?;
+
+    let mut packages = ParsedPackages::default();
+    let mut lines = vec![];
+    for (name_string, package) in parsed.into_iter() {
+        // `name_string` is, for example, "legacyPackages.x86_64-linux.auctex"
+        // Keep everything after the second '.' to get the package "name".
+        // This is different from package.pname, which is often, but not
+        // always, the name of the executable.
+        let name_vec: Vec<&str> = name_string.splitn(3, '.').collect();
+        let name = name_vec.get(2).ok_or("Can't get package name from JSON.")?;
+        lines.push(cache_line(name, &package.version, &package.description));
+
+        let attribute_set = format!("{}.{}", name_vec[0], name_vec[1]);
+        if !packages.attribute_sets.contains(&attribute_set) {
+            packages.attribute_sets.push(attribute_set);
+        }
+        packages.meta.insert(
+            name.to_string(),
+            PackageMeta {
+                pname: Some(package.pname),
+                ..Default::default()
+            },
+        );
+    }
+    lines.sort();
+    packages.lines = lines.join("\n");
+    Ok(packages)
+}
+
+/// Parse package info from `nix-env -qaP --json --meta` to
+/// (NAME\tVERSION\tDESCRIPTION) lines
+fn parse_nix_env_json_to_lines(raw_output: &str) -> Result<ParsedPackages, Box<dyn Error>> {
+    let parsed: HashMap<String, NixEnvPackage> =
+        serde_json::from_str(raw_output).map_err(|err| format!("Can't parse JSON: {err}"))?;
+
+    let mut packages = ParsedPackages::default();
+    let mut lines = vec![];
+    for (name, package) in parsed.into_iter() {
+        // `name` is the attribute path, e.g. "nixos.auctex"
+        lines.push(cache_line(
+            &name,
+            &package.version,
+            package.meta.description.as_deref().unwrap_or(""),
+        ));
+        packages
+            .meta
+            .insert(name, PackageMeta::new(package.pname, package.meta));
+    }
+    lines.sort();
+    packages.lines = lines.join("\n");
+    Ok(packages)
+}
+
+/// Nix function mapping the packages of an attribute set to their `meta`
+///
+/// Attributes failing to evaluate become `null`, licenses and maintainers are
+/// stripped to the parts `nps` uses, so they can be serialized to JSON.
+const FLAKE_META_FUNCTION: &str = r#"packages: builtins.mapAttrs (name: package:
+  let
+    safe = value:
+      let result = builtins.tryEval (builtins.deepSeq value value);
+      in if result.success then result.value else null;
+    list = value: if builtins.isList value then value else [ value ];
+    isPackage = safe (builtins.isAttrs package && package ? meta) == true;
+    meta = if isPackage then package.meta else { };
+  in {
+    mainProgram = safe (meta.mainProgram or null);
+    license = safe (map (license:
+      if builtins.isAttrs license then {
+        spdxId = license.spdxId or null;
+        shortName = license.shortName or null;
+        fullName = license.fullName or null;
+      } else license) (list (meta.license or [ ])));
+    homepage = safe (meta.homepage or null);
+    platforms = safe (builtins.filter builtins.isString (meta.platforms or [ ]));
+    maintainers = safe (map (maintainer: {
+      name = maintainer.name or null;
+      github = maintainer.github or null;
+    }) (builtins.filter builtins.isAttrs (meta.maintainers or [ ])));
+    broken = safe (meta.broken or false);
+    unfree = safe (meta.unfree or false);
+    insecure = safe (meta.insecure or false);
+  }) packages"#;
+
+/// Get `meta` attributes of the packages of a flake's attribute set
+///
+/// `nix search` doesn't report them. Only top-level packages are evaluated,
+/// attributes failing to evaluate are left empty.
+fn get_flake_meta(
+    flake: &str,
+    attribute_set: &str,
+) -> Result<HashMap<String, NixMeta>, Box<dyn Error>> {
+    let output = Command::new("nix")
+        .arg("--extra-experimental-features")
+        .arg("nix-command flakes")
+        .arg("eval")
+        .arg(format!("{flake}#{attribute_set}"))
+        .arg("--json")
+        .arg("--apply")
+        .arg(FLAKE_META_FUNCTION)
+        .output()
+        .map_err(|err| format!("`nix eval` failed: {err}"))?;
+    if !output.status.success() {
+        return Err(format!(
+            "`nix eval` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(|err| format!("Can't parse JSON: {err}").into())
+}
+
+/// Parse legacy cache lines (NAME VERSION DESCRIPTION) to the current format
+fn parse_legacy_cache_to_lines(legacy_content: &str) -> String {
+    let mut lines = vec![];
+    for line in legacy_content.lines() {
+        if line.is_empty() {
+            continue;
+        }
+        let split_line: Vec<&str> = line.splitn(3, ' ').collect();
+
+        #[allow(clippy::get_first)] // supress clippy warning for this block
+        let name = split_line.get(0).unwrap_or(&"");
+        let version = split_line.get(1).unwrap_or(&"");
+        let description = split_line.get(2).unwrap_or(&"");
+        lines.push(cache_line(name, version, description));
+    }
+    lines.join("\n")
+}
+
+/// Split the content of a cache file into header and package lines
+///
+/// The header is `None` for caches in the legacy format without header.
+fn parse_cache(content: &str) -> Result<(Option<CacheHeader>, &str), Box<dyn Error>> {
+    let (header, body) = split_cache(content)?;
+    if let Some(header) = &header {
+        if header.format != CACHE_FORMAT {
+            return Err(format!(
+                "Cache format {} is not supported, expected {CACHE_FORMAT}",
+                header.format
+            )
+            .into());
+        }
+    }
+
+    Ok((header, body))
+}
+
+/// Split the content of a cache file of any format into header and package lines
+fn split_cache(content: &str) -> Result<(Option<CacheHeader>, &str), Box<dyn Error>> {
+    // Legacy caches start directly with a package name
+    if !content.starts_with('{') {
+        return Ok((None, content));
+    }
+
+    let (header_line, body) = content.split_once('\n').unwrap_or((content, ""));
+    let header: CacheHeader = serde_json::from_str(header_line)
+        .map_err(|err| format!("Can't parse cache header: {err}"))?;
+
+    Ok((Some(header), body))
+}
+
+/// Seconds since the UNIX epoch
+fn unix_timestamp(time: SystemTime) -> Result<u64, Box<dyn Error>> {
+    Ok(time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| format!("Can't get timestamp: {err}"))?
+        .as_secs())
+}
+
+/// Atomically write header and package lines to the cache file
+pub(crate) fn write_cache(
+    file_path: &Path,
+    header: &CacheHeader,
+    body: &str,
+) -> Result<(), Box<dyn Error>> {
+    let header_line =
+        serde_json::to_string(header).map_err(|err| format!("Can't serialize header: {err}"))?;
+    write_atomically(file_path, format!("{}\n{}", header_line, body).as_bytes())?;
+    write_index(file_path, header, body)
+}
+
+/// Lowercase name of a cache line, the sort key of the name index
+fn index_key(line: &str) -> String {
+    line.split('\t').next().unwrap_or("").to_lowercase()
+}
+
+impl NameIndex {
+    const HEADER_LEN: usize = 8 + 4 + 4 + 8 + 8;
+    const ENTRY_LEN: usize = 4 + 4;
+
+    /// Build the index of a cache body
+    fn build(header: &CacheHeader, body: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut entries = vec![];
+        let mut start = 0;
+        for line in body.split_inclusive('\n') {
+            let content = line.strip_suffix('\n').unwrap_or(line);
+            if !content.is_empty() {
+                entries.push((index_key(content), start, content.len()));
+            }
+            start += line.len();
+        }
+        entries.sort();
+
+        let to_u32 = |number: usize| {
+            u32::try_from(number).map_err(|_| "Cache is too large to be indexed".to_string())
+        };
+        let mut index = Vec::with_capacity(Self::HEADER_LEN + entries.len() * Self::ENTRY_LEN);
+        index.extend_from_slice(INDEX_MAGIC);
+        index.extend_from_slice(&INDEX_FORMAT.to_le_bytes());
+        index.extend_from_slice(&to_u32(entries.len())?.to_le_bytes());
+        index.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        index.extend_from_slice(&header.created.to_le_bytes());
+        for (_key, start, len) in entries {
+            index.extend_from_slice(&to_u32(start)?.to_le_bytes());
+            index.extend_from_slice(&to_u32(len)?.to_le_bytes());
+        }
+        Ok(index)
+    }
+
+    /// Memory-map the index of a cache file
+    ///
+    /// Returns `None` if there is no index, or if it doesn't belong to the
+    /// cache created at `created` with `body`.
+    pub fn open(
+        cache_file: &Path,
+        created: u64,
+        body: &str,
+    ) -> Result<Option<NameIndex>, Box<dyn Error>> {
+        let file_path = index_file_path(cache_file);
+        let file = match fs::File::open(&file_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(format!("Can't open index {file_path:?}: {err}").into()),
+        };
+        // SAFETY: the index is replaced atomically, never modified in place
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|err| format!("Can't map index {file_path:?}: {err}"))?;
+
+        let index = NameIndex { mmap };
+        let header_ok = index.mmap.len() >= Self::HEADER_LEN
+            && &index.mmap[..8] == INDEX_MAGIC
+            && index.read_u32(8) == INDEX_FORMAT
+            && index.read_u64(16) == body.len() as u64
+            && index.read_u64(24) == created
+            && index.mmap.len() == Self::HEADER_LEN + index.len() * Self::ENTRY_LEN;
+        match header_ok {
+            true => Ok(Some(index)),
+            false => Ok(None),
+        }
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.mmap[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.mmap[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Number of packages
+    pub fn len(&self) -> usize {
+        self.read_u32(12) as usize
+    }
+
+    /// Start and length of the line of the `position`th package
+    fn entry(&self, position: usize) -> (usize, usize) {
+        let offset = Self::HEADER_LEN + position * Self::ENTRY_LEN;
+        (
+            self.read_u32(offset) as usize,
+            self.read_u32(offset + 4) as usize,
+        )
+    }
+
+    /// Line of the `position`th package, empty if the index is broken
+    fn line<'a>(&self, body: &'a str, position: usize) -> &'a str {
+        let (start, len) = self.entry(position);
+        body.get(start..start + len).unwrap_or("")
+    }
+
+    /// Position of the first package for which `predicate` is false
+    ///
+    /// Like `slice::partition_point`, `predicate` needs to be true for all
+    /// packages before those it is false for.
+    fn partition_point<P: Fn(&str) -> bool>(&self, predicate: P, body: &str) -> usize {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match predicate(&index_key(self.line(body, middle))) {
+                true => low = middle + 1,
+                false => high = middle,
+            }
+        }
+        low
+    }
+
+    /// Lines of packages named `name`, or starting with it if `prefix` is set
+    ///
+    /// Lines are returned in the order of the cache body.
+    pub fn lookup<'a>(
+        &self,
+        body: &'a str,
+        name: &str,
+        prefix: bool,
+        ignore_case: bool,
+    ) -> Vec<&'a str> {
+        let key = name.to_lowercase();
+        let first = self.partition_point(|line_key| line_key < key.as_str(), body);
+        let last = self.partition_point(
+            |line_key| {
+                line_key < key.as_str() || (prefix && line_key.starts_with(&key)) || line_key == key
+            },
+            body,
+        );
+
+        let mut entries: Vec<(usize, usize)> =
+            (first..last).map(|position| self.entry(position)).collect();
+        entries.sort();
+        entries
+            .into_iter()
+            .filter_map(|(start, len)| body.get(start..start + len))
+            .filter(|line| {
+                let line_name = line.split('\t').next().unwrap_or("");
+                let (line_name, name) = match ignore_case {
+                    true => (line_name.to_lowercase(), name.to_lowercase()),
+                    false => (line_name.to_string(), name.to_string()),
+                };
+                match prefix {
+                    true => line_name.starts_with(&name),
+                    false => line_name == name,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Atomically write the name index next to the cache file
+fn write_index(cache_file: &Path, header: &CacheHeader, body: &str) -> Result<(), Box<dyn Error>> {
+    let index = NameIndex::build(header, body)?;
+    write_atomically(&index_file_path(cache_file), &index)
+}
+
+/// Package metadata file, stored as JSON next to the cache file
+#[derive(Deserialize, Serialize)]
+struct MetaFile<P> {
+    /// Creation time of the cache the metadata belongs to
+    created: u64,
+    /// Metadata by package name
+    packages: P,
+}
+
+/// Atomically write package metadata next to the cache file
+fn write_meta(
+    cache_file: &Path,
+    created: u64,
+    packages: &HashMap<String, PackageMeta>,
+) -> Result<(), Box<dyn Error>> {
+    let content = serde_json::to_string(&MetaFile { created, packages })
+        .map_err(|err| format!("Can't serialize metadata: {err}"))?;
+    write_atomically(&meta_file_path(cache_file), content.as_bytes())
+}
+
+/// Load package metadata of the cache created at `created`, keyed by package name
+///
+/// Metadata written for another cache, e.g. by an interrupted refresh or an
+/// older `nps`, is ignored like a missing file.
+pub(crate) fn load_meta(
+    cache_file: &Path,
+    created: u64,
+) -> Result<HashMap<String, PackageMeta>, Box<dyn Error>> {
+    let file_path = meta_file_path(cache_file);
+    let content = fs::read_to_string(&file_path).map_err(|err| {
+        format!("Can't read package metadata {file_path:?}, refresh the cache with `nps -r`: {err}")
+    })?;
+    match serde_json::from_str::<MetaFile<HashMap<String, PackageMeta>>>(&content) {
+        Ok(meta) if meta.created == created => Ok(meta.packages),
+        _ => Err(format!(
+            "Package metadata {file_path:?} doesn't match the cache, refresh the cache with `nps -r`"
+        )
+        .into()),
+    }
+}
+
+/// Take the advisory lock of a cache file, waiting for another refresh
+///
+/// The lock is released when the returned lock file is dropped. Also returns
+/// whether there was another refresh to wait for. Waiting stops once `cancel`
+/// is set.
+fn lock_cache(
+    cache_file: &Path,
+    report: &mut dyn FnMut(RefreshProgress),
+    cancel: &AtomicBool,
+) -> Result<(fs::File, bool), Box<dyn Error>> {
+    let lock_path = lock_file_path(cache_file);
+    if let Some(cache_folder) = lock_path.parent() {
+        fs::create_dir_all(cache_folder).map_err(|err| format!("Can't create folder: {err}"))?;
+    }
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|err| format!("Can't open lock file {}: {err}", lock_path.display()))?;
+
+    let mut waited = false;
+    // Poll instead of blocking, so waiting can be cancelled
+    loop {
+        match flock(&lock_file, FlockOperation::NonBlockingLockExclusive) {
+            Ok(()) => return Ok((lock_file, waited)),
+            Err(rustix::io::Errno::WOULDBLOCK) => {}
+            Err(err) => return Err(format!("Can't lock {}: {err}", lock_path.display()).into()),
+        }
+        if !waited {
+            let wait_message = format!(
+                "Another refresh of {} is in progress, waiting for it to finish...",
+                cache_file.display()
+            );
+            log::info!("{}", wait_message);
+            report(RefreshProgress::Message(&wait_message));
+            waited = true;
+        }
+        if cancel.load(Ordering::SeqCst) {
+            return Err("Refresh cancelled".into());
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+/// Atomically write content to a file in the cache folder
+fn write_atomically(file_path: &Path, content: &[u8]) -> Result<(), Box<dyn Error>> {
+    let cache_folder = file_path
+        .parent()
+        .ok_or("Can't get cache folder from file path")?;
+
+    log::trace!("trying to create folder: {:?}", cache_folder);
+    // Create cache folder, if not exists
+    fs::create_dir_all(cache_folder).map_err(|err| format!("Can't create folder: {err}"))?;
+    log::trace!("folder created");
+
+    log::trace!("cache_folder: {:?}", cache_folder);
+    log::trace!("file_path: {:?}", &file_path);
+
+    // Atomic Writing: Write first to a tmp file, then persist (move) it to destination
+    let tempfile = NamedTempFile::new_in(cache_folder)
+        .map_err(|err| format!("Can't create temp file: {err}"))?;
+    log::trace!("tempfile: {:?}", &tempfile);
+    log::trace!("trying to write tempfile");
+    tempfile
+        .as_file()
+        .write_all(content)
+        .map_err(|err| format!("Can't write to temp file: {err}"))?;
+    log::trace!("tempfile written");
+
+    // Temp files are private, but other users read caches in the system cache folder
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tempfile
+            .as_file()
+            .set_permissions(fs::Permissions::from_mode(0o644))
+            .map_err(|err| format!("Can't set permissions of temp file: {err}"))?;
+    }
+
+    tempfile
+        .persist(file_path)
+        .map_err(|err| format!("Can't persist temp file: {err}"))?;
+    log::trace!("tempfile persisted");
+
+    Ok(())
+}
+
+/// Read the cache file, migrating legacy caches
+///
+/// Caches of other formats are an error, they need a refresh.
+pub(crate) fn load_cache(
+    flake: Option<&str>,
+    file_path: &Path,
+) -> Result<(CacheHeader, String), Box<dyn Error>> {
+    let content = fs::read_to_string(file_path)
+        .map_err(|err| format!("Can't open file {}: {err}", file_path.display()))?;
+
+    match parse_cache(&content)? {
+        (Some(header), body) => Ok((header, body.to_string())),
+        (None, legacy_body) => {
+            log::info!("Migrating cache to the current format.");
+            let header = legacy_header(flake, file_path)?;
+            let body = parse_legacy_cache_to_lines(legacy_body);
+            write_cache(file_path, &header, &body)?;
+
+            Ok((header, body))
+        }
+    }
+}
+
+/// Check if flakes are enabled
+fn check_flakes_enabled() -> Result<bool, Box<dyn Error>> {
+    let probe_for_flakes = Command::new("nix")
+        .arg("--extra-experimental-features")
+        .arg("nix-command")
+        .arg("config")
+        .arg("show")
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Can't execute `nix` command: {err}"))?;
+    let find_experimental_features = Command::new("grep")
+        .arg("^experimental-features")
+        .stdin(Stdio::from(probe_for_flakes.stdout.unwrap()))
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Can't execute `grep` command: {err}"))?;
+    let find_flakes = Command::new("grep")
+        .arg("flakes")
+        .stdin(Stdio::from(find_experimental_features.stdout.unwrap()))
+        .stdout(Stdio::piped())
+        .status()
+        .map_err(|err| format!("Can't execute `grep` command: {err}"))?;
+
+    Ok(find_flakes.success())
+}
+
+/// Run the command of a refresh, reporting its progress at least every
+/// `PROGRESS_INTERVAL`
+///
+/// Returns stdout and the lines of stderr that aren't progress reports. The
+/// command is killed once `cancel` is set.
+fn run_with_progress(
+    command: &mut Command,
+    name: &str,
+    report: &mut dyn FnMut(RefreshProgress),
+    cancel: &AtomicBool,
+) -> Result<(Vec<u8>, Vec<String>), Box<dyn Error>> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("`{name}` failed: {err}"))?;
+
+    // Read both pipes at once, a full pipe would block the command
+    let mut stdout_pipe = child.stdout.take().ok_or("Can't capture stdout")?;
+    let stdout_reader = thread::spawn(move || {
+        let mut stdout = vec![];
+        stdout_pipe.read_to_end(&mut stdout).map(|_bytes| stdout)
+    });
+    let stderr_pipe = child.stderr.take().ok_or("Can't capture stderr")?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::BufReader::new(stderr_pipe)
+            .lines()
+            .map_while(Result::ok)
+        {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut attribute = String::new();
+    let mut warnings = vec![];
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            break;
+        }
+        match receiver.recv_timeout(PROGRESS_INTERVAL) {
+            // Standard logging to stderr, e.g. "evaluating 'legacyPackages.x86_64-linux.hello'..."
+            Ok(line) => match line.strip_prefix("evaluating") {
+                Some(evaluating) => {
+                    attribute = evaluating.trim().trim_matches(['\'', '.']).to_string()
+                }
+                None => warnings.push(line),
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        report(RefreshProgress::Running {
+            elapsed: start.elapsed(),
+            attribute: &attribute,
+        });
+    }
+    report(RefreshProgress::Stopped);
+
+    // The command might have died of the same Ctrl-C already
+    if cancel.load(Ordering::SeqCst) {
+        log::debug!("killing `{name}`");
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err("Refresh cancelled".into());
+    }
+
+    let status = child
+        .wait()
+        .map_err(|err| format!("`{name}` failed: {err}"))?;
+    log::trace!("`{name}` exited with {status}");
+    let stdout = stdout_reader
+        .join()
+        .map_err(|_err| "Can't read stdout")?
+        .map_err(|err| format!("Can't read stdout: {err}"))?;
+
+    Ok((stdout, warnings))
+}
+
+/// Check if requested `nps` features match system features
+///
+/// Give helpful warnings if there is a mismatch.
+fn check_for_features(
+    flakes_enabled: bool,
+    experimental: bool,
+    report: &mut dyn FnMut(RefreshProgress),
+) {
+    if flakes_enabled && !experimental {
+        let flakes_messages = [
+            "Feature mismatch:",
+            "> Your system seems to be based on flakes.",
+            "> You may want to use `nps -e=true ...` instead to enable querying flake-based packages.",
+        ];
+        for flake_message in flakes_messages {
+            report(RefreshProgress::Message(flake_message));
+            log::warn!("{}", flake_message);
+        }
+    }
+    if !flakes_enabled && experimental {
+        let channels_messages = [
+            "Feature mismatch:",
+            "> Your system seems to be based on channels.",
+            "> You may want to use `nps -e=false ...` instead to query packages from channels.",
+        ];
+        for channel_message in channels_messages {
+            report(RefreshProgress::Message(channel_message));
+            log::warn!("{}", channel_message);
+        }
+    }
+}
+
+/// Get the revision of the flake or nixpkgs channel the package info is taken from
+///
+/// This is informational only, failures are logged and ignored.
+fn get_revision(flake: Option<&str>) -> Option<String> {
+    let output = match flake {
+        Some(flake) => Command::new("nix")
+            .arg("--extra-experimental-features")
+            .arg("nix-command flakes")
+            .arg("flake")
+            .arg("metadata")
+            .arg(flake)
+            .arg("--json")
+            .output(),
+        None => Command::new("nix-instantiate")
+            .arg("--eval")
+            .arg("--json")
+            .arg("--expr")
+            .arg("(import <nixpkgs/lib>).trivial.revisionWithDefault \"\"")
+            .output(),
+    };
+    let output = match output {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            log::warn!(
+                "Can't get nixpkgs revision: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+        Err(err) => {
+            log::warn!("Can't get nixpkgs revision: {err}");
+            return None;
+        }
+    };
+
+    let parsed: serde_json::Value = match serde_json::from_slice(&output.stdout) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            log::warn!("Can't parse nixpkgs revision: {err}");
+            return None;
+        }
+    };
+    let revision = match flake {
+        Some(_) => parsed
+            .get("revision")
+            .and_then(|revision| revision.as_str()),
+        None => parsed.as_str(),
+    };
+
+    revision
+        .filter(|revision| !revision.is_empty())
+        .map(|revision| revision.to_string())
+}
+
+/// Fetch new package info of channels (`None`) or a flake and write it to the
+/// cache in `cache_folder`
+///
+/// Messages for the user and the progress of the `nix` command are passed to
+/// `report`, nothing is printed. Setting `cancel`, e.g. from a Ctrl-C handler,
+/// kills the `nix` command and leaves the cache folder untouched.
+pub fn refresh(
+    cache_folder: &Path,
+    flake: Option<&str>,
+    report: &mut dyn FnMut(RefreshProgress),
+    cancel: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let file_path = cache_file_path(cache_folder, flake);
+    let flakes_enabled = check_flakes_enabled()?;
+    // Print helpful warnings if there is a feature mismatch
+    // between the system setup and the `nps` usage.
+    check_for_features(flakes_enabled, flake.is_some(), report);
+
+    // One refresh of a cache at a time, searches keep reading the old cache
+    // until the new one is moved in place
+    let created = cache_created(&file_path).ok().flatten();
+    let (_lock_file, waited) = lock_cache(&file_path, report, cancel)?;
+    if waited && cache_created(&file_path).ok().flatten() != created {
+        let done_message = "Done. The other refresh updated the cache already.";
+        log::info!("{}", done_message);
+        report(RefreshProgress::Message(done_message));
+        return Ok(());
+    }
+
+    let cache_start_message = "Refreshing cache. This might take a while...";
+    log::info!("{}", cache_start_message);
+    report(RefreshProgress::Message(cache_start_message));
+
+    log::trace!("file_path: {:?}", file_path);
+
+    let (mut command, name) = match flake {
+        Some(flake) => {
+            let mut command = Command::new("nix");
+            command
+                .arg("--extra-experimental-features")
+                .arg("nix-command flakes")
+                .arg("search")
+                .arg(flake)
+                .arg("^")
+                .arg("--json");
+            (command, "nix search")
+        }
+        None => {
+            let mut command = Command::new("nix-env");
+            command.arg("-qaP").arg("--json").arg("--meta");
+            (command, "nix-env")
+        }
+    };
+    let (output, warnings) = run_with_progress(&mut command, name, report, cancel)?;
+
+    log::trace!("finished cli command");
+
+    let stdout =
+        str::from_utf8(&output).map_err(|err| format!("Can't convert stdout to UTF8: {err}"))?;
+
+    log::trace!("stdout.len(): {}", stdout.len());
+    log::trace!("warnings.len(): {}", warnings.len());
+
+    // Report warnings if stderr looks bad
+    let mut first_error = true;
+    for line in warnings {
+        if first_error {
+            log::warn!("These warnings were encountered during cache refresh (START)");
+            first_error = false;
+        }
+        log::warn!("> {}", line);
+    }
+    if !first_error {
+        log::warn!("These warnings were encountered during cache refresh (END)");
+    }
+
+    // Throw error if cache is too small
+    if stdout.len() < 10_000 {
+        log::warn!("Cache seems too small:");
+        log::warn!("> Query returned only {} lines.", stdout.len());
+        if flakes_enabled {
+            log::info!(
+                "> Did you set up your channels yet? See: https://nixos.wiki/wiki/Nix_channels"
+            );
+            log::info!(
+                "> You can also set up your system for flakes instead. See: https://nixos.wiki/wiki/Flakes"
+            );
+        }
+        log::info!("> Run with `-dddd` flag for even more information.");
+        return Err("Cache seems too small. Run with `-dd` flag for more information.".into());
+    }
+
+    let mut packages = match flake {
+        Some(_) => parse_json_to_lines(stdout),
+        None => parse_nix_env_json_to_lines(stdout),
+    }
+    .map_err(|err| format!("Can't parse JSON: {err}"))?;
+
+    // `nix search` only reports names, versions and descriptions
+    if let Some(flake) = flake {
+        for attribute_set in packages.attribute_sets.iter() {
+            if cancel.load(Ordering::SeqCst) {
+                return Err("Refresh cancelled".into());
+            }
+            let flake_meta = match get_flake_meta(flake, attribute_set) {
+                Ok(flake_meta) => flake_meta,
+                Err(err) => {
+                    log::warn!("Can't get metadata of {flake}#{attribute_set}: {err}");
+                    continue;
+                }
+            };
+            for (name, nix_meta) in flake_meta {
+                if let Some(meta) = packages.meta.get_mut(&name) {
+                    // Flags failing to evaluate are `null`
+                    let evaluated = nix_meta.broken.is_some()
+                        && nix_meta.unfree.is_some()
+                        && nix_meta.insecure.is_some();
+                    *meta = PackageMeta::new(meta.pname.take(), nix_meta);
+                    meta.evaluated = evaluated;
+                }
+            }
+        }
+    }
+    let cache_content = packages.lines;
+
+    let header = CacheHeader {
+        format: CACHE_FORMAT,
+        source: match flake {
+            Some(_) => CacheSource::Flake,
+            None => CacheSource::Channels,
+        },
+        flake: flake.map(|flake| flake.to_string()),
+        revision: get_revision(flake),
+        created: unix_timestamp(SystemTime::now())?,
+        nps_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    log::debug!("cache header: {:?}", header);
+
+    // Last chance to cancel, writing the cache is quick
+    if cancel.load(Ordering::SeqCst) {
+        return Err("Refresh cancelled".into());
+    }
+
+    // Keep the replaced cache around to list changes, moved in place like the
+    // cache itself, so `--changes` never reads a partly copied one
+    if file_path.exists() {
+        let previous =
+            fs::read(&file_path).map_err(|err| format!("Can't keep previous cache: {err}"))?;
+        write_atomically(&previous_cache_file_path(&file_path), &previous)
+            .map_err(|err| format!("Can't keep previous cache: {err}"))?;
+    }
+    write_cache(&file_path, &header, &cache_content)?;
+    write_meta(&file_path, header.created, &packages.meta)?;
+
+    let number_of_packages = cache_content.lines().count();
+    let cache_file_path_string = format!("{:?}", file_path);
+
+    let cache_end_message =
+        format!("Done. Cached info of {number_of_packages} packages in {cache_file_path_string}");
+    log::info!("{}", &cache_end_message);
+    report(RefreshProgress::Message(&cache_end_message));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{matching_lines, Query, QueryOptions};
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_cache_file_path() {
+        init();
+
+        let cache_folder = Path::new("/cache");
+        assert_eq!(
+            cache_file_path(cache_folder, None),
+            PathBuf::from("/cache/nps.cache")
+        );
+        assert_eq!(
+            cache_file_path(cache_folder, Some("nixpkgs")),
+            PathBuf::from("/cache/nps.experimental.cache")
+        );
+        assert_eq!(
+            cache_file_path(cache_folder, Some("github:nix-community/NUR")),
+            PathBuf::from("/cache/nps.flake.github%3Anix-community%2FNUR.cache")
+        );
+        assert_eq!(
+            cache_file_path(cache_folder, Some("./my flake")),
+            PathBuf::from("/cache/nps.flake..%2Fmy%20flake.cache")
+        );
+    }
+
+    #[test]
+    fn test_parse_json_to_lines() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let json = "{\
+            \"legacyPackages.x86_64-linux.mypackage\": {\
+            \"description\":\"i describe\",\
+            \"pname\":\"mypackagebinary\",\
+            \"version\":\"old\"},\
+            \
+            \"legacyPackages.x86_64-linux.myotherpackage\": {\
+            \"description\":\"i also describe\",\
+            \"pname\":\"myotherpackagebinary\",\
+            \"version\":\"fresh\"}\
+            }";
+        let desired_output = "\
+            myotherpackage\tfresh\ti also describe\n\
+            mypackage\told\ti describe\
+            ";
+        let parsed = parse_json_to_lines(json)?;
+
+        assert_eq!(parsed.lines, desired_output);
+        assert_eq!(
+            parsed.meta["mypackage"].pname,
+            Some("mypackagebinary".to_string())
+        );
+        assert_eq!(parsed.attribute_sets, vec!["legacyPackages.x86_64-linux"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nix_env_json_to_lines() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let json = "{\
+            \"nixos.mypackage\": {\
+            \"name\":\"mypackage-1.0\",\
+            \"pname\":\"mypackage\",\
+            \"version\":\"1.0\",\
+            \"meta\":{\"description\":\"my package description\",\"mainProgram\":\"mp\",\
+            \"license\":[{\"spdxId\":\"MIT\",\"fullName\":\"MIT License\"},\
+            {\"fullName\":\"Unfree\",\"free\":false}],\
+            \"homepage\":\"https://example.org\",\
+            \"platforms\":[\"x86_64-linux\",{\"kernel\":{\"name\":\"darwin\"}}],\
+            \"maintainers\":[{\"name\":\"Jane Doe\",\"github\":\"jdoe\"},{\"name\":\"John Doe\"}],\
+            \"broken\":false,\"unfree\":true,\"insecure\":false}},\
+            \
+            \"nixos.undescribed\": {\
+            \"name\":\"undescribed-2.0.1\",\
+            \"pname\":\"undescribed\",\
+            \"version\":\"2.0.1\",\
+            \"meta\":{}}\
+            }";
+        let desired_output = "\
+            nixos.mypackage\t1.0\tmy package description\n\
+            nixos.undescribed\t2.0.1\t\
+            ";
+        let parsed = parse_nix_env_json_to_lines(json)?;
+
+        assert_eq!(parsed.lines, desired_output);
+        assert_eq!(
+            parsed.meta["nixos.mypackage"],
+            PackageMeta {
+                pname: Some("mypackage".to_string()),
+                main_programs: vec!["mp".to_string()],
+                licenses: vec!["MIT".to_string(), "Unfree".to_string()],
+                homepages: vec!["https://example.org".to_string()],
+                platforms: vec!["x86_64-linux".to_string()],
+                maintainers: vec!["Jane Doe (@jdoe)".to_string(), "John Doe".to_string()],
+                unfree: true,
+                evaluated: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            parsed.meta["nixos.undescribed"],
+            PackageMeta {
+                pname: Some("undescribed".to_string()),
+                evaluated: true,
+                ..Default::default()
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cache() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let cache = "{\"format\":3,\"source\":\"flake\",\"flake\":\"nixpkgs\",\
+            \"revision\":\"abc\",\"created\":0,\"nps_version\":\"0.2.5\"}\n\
+            mypackage\tv1\tmy package description\
+            ";
+        let (header, body) = parse_cache(cache)?;
+        let header = header.ok_or("Header missing")?;
+        assert_eq!(header.source, CacheSource::Flake);
+        assert_eq!(header.revision, Some("abc".to_string()));
+        assert_eq!(body, "mypackage\tv1\tmy package description");
+
+        let legacy_cache = "mypackage v1 my package description";
+        let (header, body) = parse_cache(legacy_cache)?;
+        assert!(header.is_none());
+        assert_eq!(
+            parse_legacy_cache_to_lines(body),
+            "mypackage\tv1\tmy package description"
+        );
+
+        let future_cache = "{\"format\":999,\"source\":\"flake\",\"flake\":null,\
+            \"revision\":null,\"created\":0,\"nps_version\":\"9.9.9\"}\n";
+        assert!(parse_cache(future_cache).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_flakes_enabled() {
+        init();
+
+        // Create a temporary directory for a nix.conf file
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let nix_conf_dir = &tempdir.path().join("nix");
+        fs::create_dir_all(nix_conf_dir).unwrap();
+
+        let tempfile = NamedTempFile::new_in(&tempdir).unwrap();
+        // Enable experimental features: "nix-command" and "flakes"
+        write!(&tempfile, "experimental-features = nix-command flakes").unwrap();
+        tempfile.persist(nix_conf_dir.join("nix.conf")).unwrap();
+
+        temp_env::with_var("XDG_CONFIG_HOME", Some(&tempdir.path()), || {
+            assert!(check_flakes_enabled().unwrap())
+        });
+
+        let tempfile = NamedTempFile::new_in(&tempdir).unwrap();
+        // Disable all experimental features
+        write!(&tempfile, "experimental-features = ").unwrap();
+        tempfile.persist(nix_conf_dir.join("nix.conf")).unwrap();
+
+        temp_env::with_var("XDG_CONFIG_HOME", Some(&tempdir.path()), || {
+            assert!(!check_flakes_enabled().unwrap())
+        });
+    }
+
+    #[test]
+    fn test_run_with_progress() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            "echo \"evaluating 'legacyPackages.x86_64-linux.hello'...\" >&2; \
+            echo 'warning: something looks bad' >&2; \
+            echo '{\"hello\": {}}'",
+        );
+        let cancel = AtomicBool::new(false);
+        let mut attributes = vec![];
+        let mut report = |progress: RefreshProgress| {
+            if let RefreshProgress::Running { attribute, .. } = progress {
+                attributes.push(attribute.to_string());
+            }
+        };
+        let (stdout, warnings) = run_with_progress(&mut command, "sh", &mut report, &cancel)?;
+        assert_eq!(stdout, b"{\"hello\": {}}\n");
+        assert_eq!(warnings, vec!["warning: something looks bad"]);
+        assert!(attributes.contains(&"legacyPackages.x86_64-linux.hello".to_string()));
+
+        // Cancelling kills the command
+        let start = Instant::now();
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                cancel.store(true, Ordering::SeqCst);
+            });
+            let mut command = Command::new("sleep");
+            command.arg("10");
+            run_with_progress(&mut command, "sleep", &mut |_progress| {}, &cancel)
+        });
+        assert_eq!(result.unwrap_err().to_string(), "Refresh cancelled");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lock_cache() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let cache_folder = tempfile::tempdir()?;
+        let cache_file = cache_folder.path().join("nps.cache");
+        let cancel = AtomicBool::new(false);
+        let (lock_file, waited) = lock_cache(&cache_file, &mut |_progress| {}, &cancel)?;
+        assert!(!waited);
+        assert!(cache_folder.path().join("nps.lock").exists());
+
+        // A second refresh waits until the first one releases the lock
+        let waiting = thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                lock_cache(&cache_file, &mut |_progress| {}, &cancel)
+                    .map(|(_lock_file, waited)| waited)
+                    .map_err(|err| err.to_string())
+            });
+            thread::sleep(Duration::from_millis(300));
+            assert!(!waiting.is_finished());
+            drop(lock_file);
+            waiting.join().unwrap()
+        });
+        assert!(waiting?);
+
+        // Waiting can be cancelled
+        let (lock_file, _waited) = lock_cache(&cache_file, &mut |_progress| {}, &cancel)?;
+        cancel.store(true, Ordering::SeqCst);
+        let cancelled = lock_cache(&cache_file, &mut |_progress| {}, &cancel);
+        assert_eq!(cancelled.unwrap_err().to_string(), "Refresh cancelled");
+        drop(lock_file);
+        cancel.store(false, Ordering::SeqCst);
+
+        // Other caches can be refreshed at the same time
+        let (_lock_file, waited) = lock_cache(&cache_file, &mut |_progress| {}, &cancel)?;
+        let (_other_lock_file, other_waited) = lock_cache(
+            &cache_folder.path().join("nps.experimental.cache"),
+            &mut |_progress| {},
+            &cancel,
+        )?;
+        assert!(!waited && !other_waited);
+        Ok(())
+    }
+
+    #[test]
+    fn test_name_index() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let body = "\
+            Neovim\t0.1\tcapitalized\n\
+            neovim\t0.10.3\tVim-fork focused on extensibility and usability\n\
+            neovim-qt\t0.2.18\tNeovim client library and GUI, in Qt5\n\
+            neovide\t0.14.0\tNeovide, a simple GUI for Neovim\n\
+            vim\t9.1.0\tThe most popular clone of the VI editor";
+        let cache_folder = tempfile::tempdir()?;
+        let cache_file = cache_folder.path().join("nps.cache");
+        let header = CacheHeader {
+            format: CACHE_FORMAT,
+            source: CacheSource::Channels,
+            flake: None,
+            revision: None,
+            created: 1737504000,
+            nps_version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        write_index(&cache_file, &header, body)?;
+        let index = NameIndex::open(&cache_file, header.created, body)?.ok_or("Index missing")?;
+
+        assert_eq!(index.len(), 5);
+        assert_eq!(
+            index.lookup(body, "neovim", false, true),
+            vec![
+                "Neovim\t0.1\tcapitalized",
+                "neovim\t0.10.3\tVim-fork focused on extensibility and usability",
+            ]
+        );
+        assert_eq!(
+            index.lookup(body, "neovim", false, false),
+            vec!["neovim\t0.10.3\tVim-fork focused on extensibility and usability"]
+        );
+        assert_eq!(index.lookup(body, "neovim-", true, true).len(), 1);
+        assert_eq!(index.lookup(body, "neov", true, true).len(), 4);
+        assert!(index.lookup(body, "emacs", true, true).is_empty());
+
+        let options = QueryOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        let case_sensitive = QueryOptions::default();
+        let fixed_strings = QueryOptions {
+            fixed_strings: true,
+            ..options
+        };
+        let any = QueryOptions {
+            any: true,
+            ..options
+        };
+
+        // Indexed searches find the same packages as a full scan
+        for (terms, options) in [
+            (vec!["name:^neov"], options),
+            (vec!["name:^neovim$"], options),
+            (vec!["name:^Neovim$"], case_sensitive),
+            (vec!["name:^neo", "desc:gui"], options),
+        ] {
+            let query = Query::parse(&terms, options)?;
+            assert!(query.name_lookup().is_some());
+            assert_eq!(
+                matching_lines(body, Some(&index), &query)?,
+                matching_lines(body, None, &query)?
+            );
+        }
+
+        // Other queries need a full scan
+        for (terms, options) in [
+            (vec!["neovim"], options),
+            (vec!["name:neovim"], options),
+            (vec!["name:^neo.im"], options),
+            (vec!["name:^neovim"], fixed_strings),
+            (vec!["name:^neovim", "vim"], any),
+        ] {
+            assert!(Query::parse(&terms, options)?.name_lookup().is_none());
+        }
+
+        // Indices of other caches are ignored, also if their bodies have the same length
+        assert!(NameIndex::open(&cache_file, header.created, "vim\t9.1.0\t")?.is_none());
+        assert!(NameIndex::open(&cache_file, header.created + 1, body)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta() -> Result<(), Box<dyn Error>> {
+        let cache_folder = tempfile::tempdir()?;
+        let cache_file = cache_folder.path().join("nps.cache");
+        assert!(load_meta(&cache_file, 1737504000).is_err());
+
+        let meta = HashMap::from([(
+            "vim".to_string(),
+            PackageMeta {
+                pname: Some("vim".to_string()),
+                unfree: true,
+                ..Default::default()
+            },
+        )]);
+        write_meta(&cache_file, 1737504000, &meta)?;
+        assert_eq!(load_meta(&cache_file, 1737504000)?, meta);
+
+        // Metadata of another cache is ignored
+        assert!(load_meta(&cache_file, 1737504001).is_err());
+        Ok(())
+    }
+}
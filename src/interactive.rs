@@ -0,0 +1,431 @@
+//! Interactive picker for `nps --interactive`
+//!
+//! Matches are searched and ranked like in the regular output while typing.
+//! The terminal user interface is drawn on stderr, so the picked name can be
+//! captured from stdout, e.g. `nix shell nixpkgs#$(nps --interactive)`.
+
+use crate::{search, Cli, Colors, MatchKind, QueryTerm, SearchContext, SearchMatch};
+use nps::Cache;
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout, Position},
+    style::{Color, Modifier, Style},
+    text::{Line, Span, Text},
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    Frame, Terminal,
+};
+use std::{error::Error, io, ops::Range, panic, sync::Arc};
+
+/// Maximum number of matches to list, more don't fit on a screen anyway
+const MAX_ROWS: usize = 1000;
+
+/// Number of matches to skip with Page Up/Down
+const PAGE_ROWS: usize = 10;
+
+/// State of the picker
+struct Picker<'a> {
+    cli: &'a Cli,
+    context: &'a SearchContext,
+    terms: Vec<QueryTerm>,
+    contents: &'a [(Option<&'a str>, Cache)],
+    color: bool,
+    input: String,
+    rows: Vec<SearchMatch>,
+    total: usize,
+    list_state: ListState,
+    error: Option<String>,
+}
+
+impl<'a> Picker<'a> {
+    fn new(
+        cli: &'a Cli,
+        context: &'a SearchContext,
+        contents: &'a [(Option<&'a str>, Cache)],
+        color: bool,
+    ) -> Self {
+        let input = cli
+            .search_term
+            .iter()
+            .map(QueryTerm::to_string)
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut picker = Picker {
+            cli,
+            context,
+            terms: vec![],
+            contents,
+            color,
+            input,
+            rows: vec![],
+            total: 0,
+            list_state: ListState::default(),
+            error: None,
+        };
+        picker.update();
+        picker
+    }
+
+    /// Search again with the current input
+    fn update(&mut self) {
+        self.rows.clear();
+        self.total = 0;
+        self.error = None;
+
+        // Every word of the input is a search term
+        self.terms = self
+            .input
+            .split_whitespace()
+            .filter_map(|term| term.parse::<QueryTerm>().ok())
+            .collect();
+        if !self.terms.is_empty() || self.cli.bin.is_some() {
+            if let Err(err) = self.rank() {
+                self.error = Some(err.to_string());
+            }
+        }
+
+        self.list_state
+            .select(if self.rows.is_empty() { None } else { Some(0) });
+    }
+
+    /// Collect matches, best ones first
+    fn rank(&mut self) -> Result<(), Box<dyn Error>> {
+        self.rows = search(self.cli, self.context, &self.terms, self.contents)?;
+        self.total = self.rows.len();
+        self.rows.truncate(MAX_ROWS);
+        Ok(())
+    }
+
+    fn selected(&self) -> Option<&SearchMatch> {
+        self.list_state
+            .selected()
+            .and_then(|index| self.rows.get(index))
+    }
+
+    fn select_next(&mut self, steps: usize) {
+        if let Some(index) = self.list_state.selected() {
+            let last = self.rows.len().saturating_sub(1);
+            self.list_state.select(Some((index + steps).min(last)));
+        }
+    }
+
+    fn select_previous(&mut self, steps: usize) {
+        if let Some(index) = self.list_state.selected() {
+            self.list_state.select(Some(index.saturating_sub(steps)));
+        }
+    }
+
+    /// Style of a match type, colored like the regular output
+    fn style(&self, match_type: MatchKind) -> Style {
+        if !self.color {
+            return Style::default().add_modifier(Modifier::BOLD);
+        }
+        let color = match match_type {
+            MatchKind::Exact => &self.cli.exact_color,
+            MatchKind::Direct => &self.cli.direct_color,
+            MatchKind::Indirect => &self.cli.indirect_color,
+        };
+        let color = match color {
+            Colors::Black => Color::Black,
+            Colors::Blue => Color::Blue,
+            Colors::Green => Color::Green,
+            Colors::Red => Color::Red,
+            Colors::Cyan => Color::Cyan,
+            Colors::Magenta => Color::Magenta,
+            Colors::Yellow => Color::Yellow,
+            Colors::White => Color::White,
+        };
+        Style::default().fg(color).add_modifier(Modifier::BOLD)
+    }
+
+    /// Split `text` into spans, highlighting the search terms found in it
+    fn highlight(text: &'a str, ranges: &[Range<usize>], style: Style) -> Vec<Span<'a>> {
+        let mut spans = vec![];
+        let mut position = 0;
+        for range in ranges {
+            spans.push(Span::raw(&text[position..range.start]));
+            spans.push(Span::styled(&text[range.clone()], style));
+            position = range.end;
+        }
+        spans.push(Span::raw(&text[position..]));
+        spans
+    }
+}
+
+/// Pick a match interactively, returns `None` if nothing was picked
+pub fn run(
+    cli: &Cli,
+    context: &SearchContext,
+    contents: &[(Option<&str>, Cache)],
+    color: bool,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let mut picker = Picker::new(cli, context, contents, color);
+
+    // Restore the terminal before a panic is reported, the report would be
+    // garbled in raw mode and vanish with the alternate screen
+    let default_hook: Arc<dyn Fn(&panic::PanicHookInfo) + Send + Sync> =
+        Arc::from(panic::take_hook());
+    let hook = Arc::clone(&default_hook);
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal();
+        hook(info);
+    }));
+
+    let picked = terminal::enable_raw_mode()
+        .map_err(|err| format!("Can't enable raw mode: {err}").into())
+        .and_then(|()| {
+            execute!(io::stderr(), EnterAlternateScreen)
+                .map_err(|err| format!("Can't enter alternate screen: {err}").into())
+        })
+        .and_then(|()| {
+            Terminal::new(CrosstermBackend::new(io::stderr()))
+                .map_err(|err| format!("Can't set up terminal: {err}").into())
+        })
+        .and_then(|mut terminal| pick(&mut terminal, &mut picker));
+
+    // Restore the terminal, also if picking failed
+    let restored = restore_terminal();
+    let _ = panic::take_hook();
+    panic::set_hook(Box::new(move |info| default_hook(info)));
+    restored?;
+
+    picked
+}
+
+/// Leave the alternate screen and raw mode
+fn restore_terminal() -> Result<(), Box<dyn Error>> {
+    execute!(io::stderr(), LeaveAlternateScreen)
+        .map_err(|err| format!("Can't leave alternate screen: {err}"))?;
+    terminal::disable_raw_mode().map_err(|err| format!("Can't disable raw mode: {err}"))?;
+    Ok(())
+}
+
+/// Handle key presses until a match is picked, or the picker is quit
+fn pick<B: Backend>(
+    terminal: &mut Terminal<B>,
+    picker: &mut Picker,
+) -> Result<Option<String>, Box<dyn Error>> {
+    loop {
+        terminal
+            .draw(|frame| draw(frame, picker))
+            .map_err(|err| format!("Can't draw picker: {err}"))?;
+
+        let Event::Key(key) = event::read().map_err(|err| format!("Can't read key: {err}"))? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+
+        match (key.code, key.modifiers) {
+            (KeyCode::Esc, _) | (KeyCode::Char('c'), KeyModifiers::CONTROL) => return Ok(None),
+            (KeyCode::Enter, _) => {
+                return Ok(picker.selected().map(|row| row.package.name.clone()))
+            }
+            (KeyCode::Up, _) | (KeyCode::Char('p'), KeyModifiers::CONTROL) => {
+                picker.select_previous(1)
+            }
+            (KeyCode::Down, _) | (KeyCode::Char('n'), KeyModifiers::CONTROL) => {
+                picker.select_next(1)
+            }
+            (KeyCode::PageUp, _) => picker.select_previous(PAGE_ROWS),
+            (KeyCode::PageDown, _) => picker.select_next(PAGE_ROWS),
+            (KeyCode::Backspace, _) => {
+                picker.input.pop();
+                picker.update();
+            }
+            (KeyCode::Char(character), KeyModifiers::NONE | KeyModifiers::SHIFT) => {
+                picker.input.push(character);
+                picker.update();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Draw input line, list of matches, preview of the selected match and status line
+fn draw(frame: &mut Frame, picker: &mut Picker) {
+    let [input_area, main_area, status_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [list_area, preview_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(main_area);
+
+    // Input line, with the cursor after the input
+    let input =
+        Paragraph::new(format!("> {}", picker.input)).block(Block::bordered().title(" nps "));
+    frame.render_widget(input, input_area);
+    frame.set_cursor_position(Position::new(
+        input_area.x + 3 + picker.input.chars().count() as u16,
+        input_area.y + 1,
+    ));
+
+    // List of matches, search terms highlighted in the color of their match type
+    let items: Vec<ListItem> = picker
+        .rows
+        .iter()
+        .map(|row| {
+            let package = &row.package;
+            let mut spans = Picker::highlight(
+                &package.name,
+                &package.highlights.name,
+                picker.style(package.kind),
+            );
+            spans.push(Span::raw("  "));
+            spans.push(Span::styled(
+                &package.version,
+                Style::default().add_modifier(Modifier::DIM),
+            ));
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::bordered().title(" Matches "))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol("> ");
+    frame.render_stateful_widget(list, list_area, &mut picker.list_state);
+
+    // Preview of the selected match
+    let preview = match picker.selected() {
+        Some(row) => {
+            let package = &row.package;
+            let style = picker.style(package.kind);
+            let mut lines = vec![
+                Line::from(Picker::highlight(
+                    &package.name,
+                    &package.highlights.name,
+                    style,
+                )),
+                Line::raw(format!("version: {}", package.version)),
+                Line::raw(format!("match: {:?}", package.kind).to_lowercase()),
+            ];
+            if let Some(sources) = &row.sources {
+                lines.push(Line::raw(format!("sources: {}", sources.join(","))));
+            }
+            lines.push(Line::raw(""));
+            lines.push(Line::from(Picker::highlight(
+                &package.description,
+                &package.highlights.description,
+                style,
+            )));
+            Text::from(lines)
+        }
+        None => Text::raw(""),
+    };
+    let preview = Paragraph::new(preview)
+        .wrap(Wrap { trim: false })
+        .block(Block::bordered().title(" Preview "));
+    frame.render_widget(preview, preview_area);
+
+    // Status line, or the error of the last search
+    let status = match &picker.error {
+        Some(err) => Line::styled(err.as_str(), Style::default().fg(Color::Red)),
+        None => Line::raw(format!(
+            "{}/{} matches, ↑/↓ select, Enter print name, Esc quit",
+            picker.rows.len(),
+            picker.total
+        )),
+    };
+    frame.render_widget(Paragraph::new(status), status_area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use ratatui::backend::TestBackend;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn contents() -> Vec<(Option<&'static str>, Cache)> {
+        vec![(
+            Some("nixpkgs"),
+            crate::tests::cache(
+                Some("nixpkgs"),
+                "\
+                neovim-qt\t0.2.18\tNeovim client library and GUI, in Qt5\n\
+                vimPlugins.neovim-ayu\t2024-07-08\tAyu theme for Neovim\n\
+                neovim\t0.10.3\tVim text editor fork focused on extensibility and agility\n\
+                emacs\t29.4\tThe extensible, customizable GNU text editor\
+                ",
+            ),
+        )]
+    }
+
+    #[test]
+    fn test_picker_update() {
+        init();
+
+        let cli = Cli::try_parse_from(vec!["nps", "-e=true", "neovim"]).unwrap();
+        let context = SearchContext::default();
+        let contents = contents();
+        let mut picker = Picker::new(&cli, &context, &contents, true);
+
+        let names: Vec<&str> = picker
+            .rows
+            .iter()
+            .map(|row| row.package.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["neovim", "neovim-qt", "vimPlugins.neovim-ayu"]);
+        assert_eq!(picker.rows[0].package.kind, MatchKind::Exact);
+        assert_eq!(picker.selected().unwrap().package.name, "neovim");
+
+        picker.select_next(PAGE_ROWS);
+        assert_eq!(
+            picker.selected().unwrap().package.name,
+            "vimPlugins.neovim-ayu"
+        );
+        picker.select_previous(1);
+        assert_eq!(picker.selected().unwrap().package.name, "neovim-qt");
+
+        picker.input.push_str(" desc:gnu");
+        picker.update();
+        assert!(picker.rows.is_empty());
+        assert!(picker.selected().is_none());
+
+        picker.input = "editor".to_string();
+        picker.update();
+        let names: Vec<&str> = picker
+            .rows
+            .iter()
+            .map(|row| row.package.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["neovim", "emacs"]);
+    }
+
+    #[test]
+    fn test_draw() {
+        init();
+
+        let cli = Cli::try_parse_from(vec!["nps", "-e=true", "neovim"]).unwrap();
+        let context = SearchContext::default();
+        let contents = contents();
+        let mut picker = Picker::new(&cli, &context, &contents, false);
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 12)).unwrap();
+        terminal.draw(|frame| draw(frame, &mut picker)).unwrap();
+
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("> neovim"));
+        assert!(screen.contains("│> neovim  0.10.3"));
+        assert!(screen.contains("version: 0.10.3"));
+        assert!(screen.contains("match: exact"));
+        assert!(screen.contains("3/3 matches"));
+    }
+}
@@ -0,0 +1,334 @@
+//! Find packages in the package caches of `nps`
+//!
+//! Caches are created with `refresh` and read with `Cache::load`. Search them
+//! with a `Query` to get matches sorted by relevance:
+//!
+//! ```no_run
+//! use nps::{Cache, Query, QueryOptions};
+//! use std::path::Path;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let cache_folder = Path::new("/home/user/.cache/nps");
+//! let cache = Cache::load(cache_folder, Some("nixpkgs"))?;
+//! let query = Query::parse(&["name:^neovim"], QueryOptions::default())?;
+//! for found in nps::search(&cache, &query)? {
+//!     println!("{} {} ({:?})", found.name, found.version, found.kind);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use cache::{
+    cache_changes, cache_created, cache_file_path, load_cache, load_meta, previous_cache_file_path,
+    read_any_cache, NameIndex,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+};
+
+mod cache;
+mod query;
+mod version;
+
+pub use cache::{
+    refresh, CacheChanges, CacheHeader, CacheSource, ChangedPackage, PackageMeta, RefreshProgress,
+    CACHE_FORMAT,
+};
+pub use query::{Field, Highlights, Query, QueryOptions, QueryTerm};
+pub use version::{compare_versions, VersionConstraint, VersionOperator};
+
+/// Relevance of a match
+///
+///   exact     SEARCH_TERM (in PACKAGE_NAME column)
+///   direct    SEARCH_TERMbar (in PACKAGE_NAME column)
+///   indirect  fooSEARCH_TERMbar (in any column)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    Exact,
+    Direct,
+    Indirect,
+}
+
+/// Case converter for case-insensitive searches
+fn convert_case(string: &str, ignore_case: bool) -> String {
+    match ignore_case {
+        true => string.to_lowercase(),
+        false => string.to_string(),
+    }
+}
+
+/// Package matching a query
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Match {
+    pub name: String,
+    pub version: String,
+    pub description: String,
+    pub kind: MatchKind,
+    /// Where the search terms were found
+    pub highlights: Highlights,
+}
+
+/// Cached package info of channels or a flake
+#[derive(Debug)]
+pub struct Cache {
+    pub header: CacheHeader,
+    /// Package lines (NAME\tVERSION\tDESCRIPTION)
+    pub body: String,
+    path: PathBuf,
+}
+
+impl Cache {
+    /// Read the cache of channels (`None`) or a flake from `cache_folder`
+    ///
+    /// The cache needs to exist, create it with `refresh`. Legacy caches
+    /// without header are migrated, caches of other formats are an error,
+    /// they need a refresh.
+    pub fn load(cache_folder: &Path, flake: Option<&str>) -> Result<Cache, Box<dyn Error>> {
+        let path = cache_file_path(cache_folder, flake);
+        let (header, body) = load_cache(flake, &path)?;
+        Ok(Cache { header, body, path })
+    }
+
+    /// Creation time of the cache of channels (`None`) or a flake in
+    /// `cache_folder`
+    ///
+    /// Returns `None` if there is no cache that `load` can read.
+    pub fn created(cache_folder: &Path, flake: Option<&str>) -> io::Result<Option<u64>> {
+        cache_created(&cache_file_path(cache_folder, flake))
+    }
+
+    /// Path of the cache file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name index of the cache, if there is a usable one
+    fn index(&self) -> Result<Option<NameIndex>, Box<dyn Error>> {
+        NameIndex::open(&self.path, self.header.created, &self.body)
+    }
+
+    /// Package metadata, keyed by package name
+    pub fn meta(&self) -> Result<HashMap<String, PackageMeta>, Box<dyn Error>> {
+        load_meta(&self.path, self.header.created)
+    }
+
+    /// The cache replaced by the last refresh, if there was one
+    ///
+    /// It may be of an older format, e.g. after an update of `nps`.
+    pub fn previous(&self) -> Result<Option<Cache>, Box<dyn Error>> {
+        let path = previous_cache_file_path(&self.path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let (header, body) = read_any_cache(self.header.flake.as_deref(), &path)?;
+        Ok(Some(Cache { header, body, path }))
+    }
+
+    /// Packages added, removed and updated since the `previous` cache
+    pub fn changes<'a>(&'a self, previous: &'a Cache) -> CacheChanges<'a> {
+        CacheChanges {
+            flake: self.header.flake.as_deref(),
+            previous_created: previous.header.created,
+            created: self.header.created,
+            ..cache_changes(&previous.body, &self.body)
+        }
+    }
+}
+
+/// Package name without the channel name, e.g. `nixos.`
+///
+/// Returns `None` for names of other channels.
+fn strip_channel(name: &str) -> Option<&str> {
+    name.strip_prefix("nixos.")
+        .or_else(|| name.strip_prefix("nixpkgs."))
+}
+
+/// Relevance of a package name for a search term
+///
+/// The name needs to be stripped of channel names and flake references.
+fn match_kind(name: &str, search_term: &str, ignore_case: bool) -> MatchKind {
+    let name = convert_case(name, ignore_case);
+    let search_term = convert_case(search_term, ignore_case);
+    if name == search_term {
+        MatchKind::Exact
+    } else if name.starts_with(&search_term) {
+        MatchKind::Direct
+    } else {
+        MatchKind::Indirect
+    }
+}
+
+/// Lines of a cache body matching `query`, in the order of the body
+///
+/// The name index is used if one of the terms is an anchored name like
+/// `name:^neovim` or `name:^neovim$`, otherwise the whole body is scanned.
+pub(crate) fn matching_lines<'a>(
+    body: &'a str,
+    index: Option<&NameIndex>,
+    query: &Query,
+) -> Result<Vec<&'a str>, Box<dyn Error>> {
+    let candidates: Box<dyn Iterator<Item = &'a str>> = match index.zip(query.name_lookup()) {
+        Some((index, (name, prefix))) => {
+            log::debug!("looking up {name:?} in name index");
+            Box::new(
+                index
+                    .lookup(body, name, prefix, query.options().ignore_case)
+                    .into_iter(),
+            )
+        }
+        None => Box::new(body.lines()),
+    };
+
+    let mut lines = vec![];
+    for line in candidates {
+        if query.is_match(line)? {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+/// Find the packages of a cache matching `query`, sorted by relevance
+///
+/// Matches of the same kind keep the order of the cache.
+pub fn search(cache: &Cache, query: &Query) -> Result<Vec<Match>, Box<dyn Error>> {
+    let index = cache.index().unwrap_or_else(|err| {
+        log::warn!("Can't use name index: {err}");
+        None
+    });
+    let primary_term = query.primary_term();
+
+    let mut matches = vec![];
+    for line in matching_lines(&cache.body, index.as_ref(), query)? {
+        let mut columns = line.splitn(3, '\t');
+        let name = columns.next().unwrap_or("");
+        let plain_name = match cache.header.source {
+            CacheSource::Channels => strip_channel(name),
+            CacheSource::Flake => Some(name),
+        };
+        let kind = match (plain_name, primary_term) {
+            (Some(plain_name), Some(term)) => {
+                match_kind(plain_name, &term.pattern, query.options().ignore_case)
+            }
+            _ => MatchKind::Indirect,
+        };
+        let version = columns.next().unwrap_or("");
+        let description = columns.next().unwrap_or("");
+        matches.push(Match {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: description.to_string(),
+            kind,
+            highlights: query.highlights(name, version, description)?,
+        });
+    }
+    matches.sort_by_key(|found| found.kind);
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_convert_case() {
+        init();
+
+        let test_string = "abCDef";
+
+        assert_eq!(convert_case(test_string, false), "abCDef");
+        assert_eq!(convert_case(test_string, true), "abcdef");
+    }
+
+    #[test]
+    fn test_search() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let cache_folder = tempfile::tempdir()?;
+        let header = CacheHeader {
+            format: CACHE_FORMAT,
+            source: CacheSource::Channels,
+            flake: None,
+            revision: None,
+            created: 0,
+            nps_version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+        let body = "\
+            nixos.neovide\t0.14.0\tNeovide, a simple GUI for Neovim\n\
+            nixos.neovim\t0.10.3\tVim-fork focused on extensibility and usability\n\
+            nixos.neovim-qt\t0.2.18\tNeovim client library and GUI, in Qt5\n\
+            nixos.vim\t9.1.0\tThe most popular clone of the VI editor";
+        cache::write_cache(&cache_file_path(cache_folder.path(), None), &header, body)?;
+
+        let cache = Cache::load(cache_folder.path(), None)?;
+        let query = Query::parse(
+            &["neovim"],
+            QueryOptions {
+                ignore_case: true,
+                ..Default::default()
+            },
+        )?;
+        let matches = search(&cache, &query)?;
+        let found: Vec<(&str, MatchKind)> = matches
+            .iter()
+            .map(|found| (found.name.as_str(), found.kind))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("nixos.neovim", MatchKind::Exact),
+                ("nixos.neovim-qt", MatchKind::Direct),
+                ("nixos.neovide", MatchKind::Indirect),
+            ]
+        );
+        assert_eq!(matches[0].version, "0.10.3");
+        assert_eq!(matches[0].highlights.name, vec![6..12]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<(), Box<dyn Error>> {
+        init();
+
+        let cache_folder = tempfile::tempdir()?;
+        let cache_file = cache_file_path(cache_folder.path(), Some("nixpkgs"));
+        assert_eq!(Cache::created(cache_folder.path(), Some("nixpkgs"))?, None);
+        assert!(Cache::load(cache_folder.path(), Some("nixpkgs")).is_err());
+
+        // Caches of other formats need a refresh, loading them doesn't start one
+        let header = CacheHeader {
+            format: CACHE_FORMAT + 1,
+            source: CacheSource::Flake,
+            flake: Some("nixpkgs".to_string()),
+            revision: None,
+            created: 1737504000,
+            nps_version: "9.9.9".to_string(),
+        };
+        cache::write_cache(&cache_file, &header, "neovim\t0.10.3\t")?;
+        assert_eq!(Cache::created(cache_folder.path(), Some("nixpkgs"))?, None);
+        assert!(Cache::load(cache_folder.path(), Some("nixpkgs")).is_err());
+        assert!(!cache_folder.path().join("nps.experimental.lock").exists());
+
+        // Legacy caches are migrated
+        fs::write(&cache_file, "neovim 0.10.3 Vim-fork")?;
+        let cache = Cache::load(cache_folder.path(), Some("nixpkgs"))?;
+        assert_eq!(cache.header.format, CACHE_FORMAT);
+        assert_eq!(cache.body, "neovim\t0.10.3\tVim-fork");
+        assert_eq!(
+            Cache::created(cache_folder.path(), Some("nixpkgs"))?,
+            Some(cache.header.created)
+        );
+        assert!(cache.previous()?.is_none());
+        Ok(())
+    }
+}
@@ -1,39 +1,74 @@
 use clap::builder::styling::{AnsiColor, Effects, Styles};
-use clap::{ArgAction, Parser, ValueEnum};
+use clap::{parser::ValueSource, ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
 use env_logger::Builder;
-use grep::{
-    printer::{ColorSpecs, Standard, StandardBuilder, UserColorSpec},
-    regex::RegexMatcherBuilder,
-    searcher::SearcherBuilder,
-};
 use log::LevelFilter;
-use serde::Deserialize;
+use nps::{
+    compare_versions, Cache, CacheChanges, CacheHeader, Match, MatchKind, PackageMeta, Query,
+    QueryOptions, QueryTerm, RefreshProgress, VersionConstraint,
+};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     error::Error,
-    fs,
+    ffi::OsString,
+    fmt, fs,
     io::{self, IsTerminal, Write},
-    path::PathBuf,
+    ops::Range,
+    path::{Path, PathBuf},
     process::{Command, ExitCode, Stdio},
     str,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tempfile::NamedTempFile;
-use termcolor::{Buffer, BufferWriter};
+
+mod interactive;
+use termcolor::{Buffer, BufferWriter, ColorSpec, WriteColor};
+
+/// Prefix of the environment variables, config file keys are named without it
+const ENV_PREFIX: &str = "NIX_PACKAGE_SEARCH_";
+
+/// Maximum number of "did you mean" suggestions if nothing matches.
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Redraw interval of the progress line while refreshing
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Progress lines are cut to this width, so they don't wrap
+const PROGRESS_WIDTH: usize = 80;
+
+/// Set by the Ctrl-C handler to cancel the running refresh
+static CANCEL_REFRESH: AtomicBool = AtomicBool::new(false);
+
+/// Whether a refresh is running, so Ctrl-C cancels it instead of exiting
+static REFRESHING: AtomicBool = AtomicBool::new(false);
 
 /// Default settings for `nps`.
 ///
 /// They are also listed in the `-h`/`--help` commands.
 const DEFAULTS: Defaults = Defaults {
-    cache_folder: ".nix-package-search", // /home/USER/...
-    cache_file: "nps.cache",             // not user settable
+    any: false,
+    cache_folder: "nps", // $XDG_CACHE_HOME/...
+    system_cache_folder: "/var/cache/nps",
+    legacy_cache_folder: ".nix-package-search", // /home/USER/..., moved to `cache_folder`
+    cache_file: "nps.cache",                    // not user settable
     experimental: false,
     experimental_cache_file: "nps.experimental.cache", // not user settable
+    fixed_strings: false,
+    flake: "nixpkgs", // used with `experimental`, if no other flake is requested
     color_mode: clap::ColorChoice::Auto,
     columns: ColumnsChoice::All,
     flip: false,
+    fuzzy: false,
     ignore_case: true,
+    merge: false,
+    no_broken: false,
+    no_insecure: false,
+    no_unfree: false,
+    output: OutputChoice::Text,
     print_separator: true,
     quiet: false,
+    sort: SortChoice::Name,
+    stale_cache: StaleCacheChoice::Warn,
 
     exact_color: Colors::Magenta,
     direct_color: Colors::Blue,
@@ -50,7 +85,7 @@ const DEFAULTS: Defaults = Defaults {
 ///   exact     SEARCH_TERM (in PACKAGE_NAME column)
 ///   direct    SEARCH_TERMbar (in PACKAGE_NAME column)
 ///   indirect  fooSEARCH_TERMbar (in any column)
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(
     author,
     version,
@@ -66,6 +101,19 @@ struct Cli {
     // env: read env var if flag not present
     // takes_values: accept values from command line
     // hide: hides the option from `-h`, those parameters are set via env vars
+    /// Match any SEARCH_TERM instead of all of them
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.any,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        conflicts_with = "fuzzy",
+        env = "NIX_PACKAGE_SEARCH_ANY"
+    )]
+    any: bool,
+
     /// Highlight search matches in color
     #[arg(
         short,
@@ -115,6 +163,33 @@ struct Cli {
     )]
     experimental: bool,
 
+    /// Search for SEARCH_TERMs literally, not as regexes
+    #[arg(
+        short = 'F',
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.fixed_strings,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        env = "NIX_PACKAGE_SEARCH_FIXED_STRINGS"
+    )]
+    fixed_strings: bool,
+
+    /// Search packages of these flakes, implies --experimental
+    ///
+    /// Takes any flake reference, e.g. `nixpkgs/nixos-unstable`,
+    /// `github:nix-community/NUR` or a local path. Use several times, or
+    /// separate by commas, to search multiple flakes at once. `--flake=`
+    /// clears flakes set in the environment or config file.
+    #[arg(
+        long,
+        require_equals = true,
+        value_delimiter = ',',
+        env = "NIX_PACKAGE_SEARCH_FLAKES"
+    )]
+    flake: Vec<String>,
+
     /// Flip the order of matches and sorting
     #[arg(
         short,
@@ -128,6 +203,18 @@ struct Cli {
     )]
     flip: bool,
 
+    /// Tolerate typos, only match package names
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.fuzzy,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        env = "NIX_PACKAGE_SEARCH_FUZZY"
+    )]
+    fuzzy: bool,
+
     /// Ignore case
     #[arg(
         short,
@@ -141,6 +228,37 @@ struct Cli {
     )]
     ignore_case: bool,
 
+    /// Pick a match interactively, print its name
+    ///
+    /// Matches are updated while typing SEARCH_TERMs. Use arrow keys to select
+    /// a match, Enter to print its name and Esc to quit.
+    #[arg(long)]
+    interactive: bool,
+
+    /// Search channels and flakes at once, merge matches by name
+    #[arg(
+        short,
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.merge,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        env = "NIX_PACKAGE_SEARCH_MERGE"
+    )]
+    merge: bool,
+
+    /// Output format of matches
+    #[arg(
+        short,
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.output,
+        value_enum,
+        env = "NIX_PACKAGE_SEARCH_OUTPUT"
+    )]
+    output: OutputChoice,
+
     /// Suppress non-debug messages
     #[arg(
         short,
@@ -158,6 +276,26 @@ struct Cli {
     #[arg(short, long)]
     refresh: bool,
 
+    /// Only use the system cache, e.g. to refresh it with `sudo nps -r --system`
+    #[arg(long)]
+    system: bool,
+
+    /// Run the package named SEARCH_TERM
+    #[arg(long, group = "action", conflicts_with = "interactive")]
+    run: bool,
+
+    /// Open a shell with the packages named SEARCH_TERMs
+    #[arg(long, group = "action", conflicts_with = "interactive")]
+    shell: bool,
+
+    /// Install the packages named SEARCH_TERMs
+    #[arg(long, group = "action", conflicts_with = "interactive")]
+    install: bool,
+
+    /// Print the command of --run, --shell or --install instead of running it
+    #[arg(long, requires = "action")]
+    dry_run: bool,
+
     /// Separate match types with a newline
     #[arg(
         short,
@@ -171,11 +309,147 @@ struct Cli {
     )]
     separate: bool,
 
-    /// Search for any SEARCH_TERM in package names, description or versions
+    /// Consider the cache stale if it is older than this, e.g. 12h, 7d or 2w
+    #[arg(
+        long,
+        require_equals = true,
+        value_parser = parse_cache_age,
+        env = "NIX_PACKAGE_SEARCH_MAX_CACHE_AGE"
+    )]
+    max_cache_age: Option<u64>,
+
+    /// What to do if the cache is older than MAX_CACHE_AGE
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.stale_cache,
+        value_enum,
+        env = "NIX_PACKAGE_SEARCH_STALE_CACHE"
+    )]
+    stale_cache: StaleCacheChoice,
+
+    /// Only show packages with versions matching all CONSTRAINTs, e.g. `>=3.11`
+    ///
+    /// Operators are <, <=, =, !=, >= and >, versions are compared like
+    /// `builtins.compareVersions` does. Separate several constraints with
+    /// commas, e.g. `>=3.11,<3.13`. Packages without version never match.
+    #[arg(
+        long,
+        require_equals = true,
+        value_name = "CONSTRAINT",
+        value_delimiter = ',',
+        env = "NIX_PACKAGE_SEARCH_VERSION_CONSTRAINT"
+    )]
+    version_constraint: Vec<VersionConstraint>,
+
+    /// Sort matches of the same match type by
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.sort,
+        value_enum,
+        env = "NIX_PACKAGE_SEARCH_SORT"
+    )]
+    sort: SortChoice,
+
+    /// Search packages providing the executable BIN, e.g. `rg`
+    ///
+    /// Packages with BIN as main program are exact matches, packages named BIN
+    /// without main program are direct matches, and packages with BIN in their
+    /// main program or name are indirect matches. SEARCH_TERMs are optional.
+    #[arg(long, require_equals = true, conflicts_with_all = ["fuzzy", "action"])]
+    bin: Option<String>,
+
+    /// Show all metadata of the package ATTR, e.g. `ripgrep`
+    ///
+    /// Prints the version, description, main program, licenses, homepage,
+    /// platforms, maintainers and broken, unfree and insecure status.
+    #[arg(
+        long,
+        require_equals = true,
+        value_name = "ATTR",
+        conflicts_with_all = ["bin", "interactive", "action"],
+    )]
+    info: Option<String>,
+
+    /// List packages added, removed or updated by the last refresh
+    ///
+    /// Compares the cache to the one it replaced. Combine with --refresh to
+    /// refresh first.
+    #[arg(long, conflicts_with_all = ["bin", "info", "interactive", "action"])]
+    changes: bool,
+
+    /// Only show packages with one of these licenses, e.g. `mit,apache-2.0`
+    ///
+    /// Licenses are given by SPDX identifier, or by name if they don't have
+    /// one. Case is ignored.
+    #[arg(
+        long,
+        require_equals = true,
+        value_delimiter = ',',
+        env = "NIX_PACKAGE_SEARCH_LICENSE"
+    )]
+    license: Vec<String>,
+
+    /// Only show packages available on PLATFORM, e.g. `aarch64-linux`
+    #[arg(long, require_equals = true, env = "NIX_PACKAGE_SEARCH_PLATFORM")]
+    platform: Option<String>,
+
+    /// Hide packages with unfree licenses
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.no_unfree,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        env = "NIX_PACKAGE_SEARCH_NO_UNFREE"
+    )]
+    no_unfree: bool,
+
+    /// Hide packages marked as broken
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.no_broken,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        env = "NIX_PACKAGE_SEARCH_NO_BROKEN"
+    )]
+    no_broken: bool,
+
+    /// Hide packages marked as insecure
+    #[arg(
+        long,
+        require_equals = true,
+        default_value_t = DEFAULTS.no_insecure,
+        default_missing_value = "true",
+        num_args = 0..=1,
+        action = ArgAction::Set,
+        env = "NIX_PACKAGE_SEARCH_NO_INSECURE"
+    )]
+    no_insecure: bool,
+
+    /// Use the settings of profile NAME in the config file
+    #[arg(long, require_equals = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Print the effective settings and where they come from
+    #[arg(long, conflicts_with_all = ["bin", "info", "interactive", "action", "changes"])]
+    print_config: bool,
+
+    /// Search for SEARCH_TERMs in package names, description or versions
+    ///
+    /// Lines need to match all SEARCH_TERMs, or any of them with --any.
+    /// Restrict a SEARCH_TERM to one column with a `name:`, `desc:` or
+    /// `version:` prefix, e.g. `name:rust desc:analyzer version:^1\.`
+    /// Matches are sorted by the first SEARCH_TERM without `desc:` or
+    /// `version:` prefix.
     #[arg(
-        required_unless_present_any = ["refresh"]
+        required_unless_present_any = ["refresh", "interactive", "bin", "info", "changes", "print_config"]
     )]
-    search_term: Option<String>,
+    search_term: Vec<QueryTerm>,
 
     // hidden vars, to be set via env vars
     /// Cache lives here
@@ -183,16 +457,23 @@ struct Cli {
         long,
         require_equals = true,
         hide = true,
-        default_value = home::home_dir()
-            .unwrap()  // We previously made sure this works.
-            .join(DEFAULTS.cache_folder)
-            .display()
-            .to_string(),
+        default_value = default_cache_folder().display().to_string(),
         value_parser = clap::value_parser!(PathBuf),
         env = "NIX_PACKAGE_SEARCH_CACHE_FOLDER_ABSOLUTE_PATH"
     )]
     cache_folder: PathBuf,
 
+    /// System cache lives here, shared by all users
+    #[arg(
+        long,
+        require_equals = true,
+        hide = true,
+        default_value = DEFAULTS.system_cache_folder,
+        value_parser = clap::value_parser!(PathBuf),
+        env = "NIX_PACKAGE_SEARCH_SYSTEM_CACHE_FOLDER"
+    )]
+    system_cache_folder: PathBuf,
+
     /// Color of EXACT matches, match SEARCH_TERM
     #[arg(
         long,
@@ -239,6 +520,20 @@ CONFIGURATION
 `nps` can be configured with environment variables. You can set these in
 the configuration file of your shell, e.g. .bashrc/.zshrc
 
+The same settings can be stored in $XDG_CONFIG_HOME/nps/config.toml, named
+without the NIX_PACKAGE_SEARCH_ prefix and in lowercase, e.g. `flip = true`.
+Tables like [profile.work] hold named sets of settings, use them with
+--profile=work. Command line options override environment variables, which
+override the config file. Show the effective settings with --print-config.
+Options without environment variable, like --bin, --info or --refresh, only
+apply to a single search and can't be set in the config file.
+
+NIX_PACKAGE_SEARCH_ANY
+  Match lines containing any of several SEARCH_TERMs, instead of all of them.
+  Matches are still sorted by the first SEARCH_TERM.
+    [default: {DEFAULT_ANY}]
+    [possible values: true, false]
+
 NIX_PACKAGE_SEARCH_EXPERIMENTAL
   Use the experimental 'nix search' command.
   It pulls information from the nix flake registries instead of nix channels.
@@ -247,6 +542,21 @@ NIX_PACKAGE_SEARCH_EXPERIMENTAL
     [default: {DEFAULT_EXPERIMENTAL}]
     [possible values: true, false]
 
+NIX_PACKAGE_SEARCH_FIXED_STRINGS
+  Search for SEARCH_TERM literally, e.g. 'c++' or 'gtk+3'. Otherwise
+  SEARCH_TERM is a regex, unless it isn't a valid one or looks like a name
+  with '+' in it.
+    [default: {DEFAULT_FIXED_STRINGS}]
+    [possible values: true, false]
+
+NIX_PACKAGE_SEARCH_FLAKES
+  Search packages of these flakes instead of '{DEFAULT_FLAKE}', implies
+  NIX_PACKAGE_SEARCH_EXPERIMENTAL. Separate multiple flakes with commas.
+  Every flake has its own cache file. An empty value clears the flakes of
+  the config file.
+    [default: unset]
+    [possible values: flake references, e.g. nixpkgs/nixos-unstable]
+
 NIX_PACKAGE_SEARCH_FLIP
   Flip the order of matches? By default most relevant matches appear below,
   which is easier to read with long output. Flipping shows most relevant
@@ -254,11 +564,27 @@ NIX_PACKAGE_SEARCH_FLIP
     [default: {DEFAULT_FLIP}]
     [possible values: true, false]
 
+NIX_PACKAGE_SEARCH_FUZZY
+  Tolerate typos in SEARCH_TERM. Only package names are matched, SEARCH_TERM
+  is not treated as a regex. Match types are
+  exact: PACKAGE_NAME is a few edits away from SEARCH_TERM
+  direct: PACKAGE_NAME starts with something a few edits away from SEARCH_TERM
+  indirect: PACKAGE_NAME contains all characters of SEARCH_TERM in order
+    [default: {DEFAULT_FUZZY}]
+    [possible values: true, false]
+
 NIX_PACKAGE_SEARCH_CACHE_FOLDER_ABSOLUTE_PATH
   Absolute path of the cache folder
     [default: {DEFAULT_CACHE_FOLDER}]
     [possible values: path]
 
+NIX_PACKAGE_SEARCH_SYSTEM_CACHE_FOLDER
+  Absolute path of the system cache folder, shared by all users. Refresh it
+  as root with `nps --refresh --system`. Searches read the fresher one of the
+  system cache and the own cache.
+    [default: {DEFAULT_SYSTEM_CACHE_FOLDER}]
+    [possible values: path]
+
 NIX_PACKAGE_SEARCH_COLUMNS
   Choose columns to show: PACKAGE_NAME plus any of PACKAGE_VERSION or
   PACKAGE_DESCRIPTION
@@ -286,11 +612,79 @@ NIX_PACKAGE_SEARCH_COLOR_MODE
     [default: {DEFAULT_COLOR_MODE}]
     [possible values: always, never, auto]
 
+NIX_PACKAGE_SEARCH_MERGE
+  Search the channels cache and the caches of all flakes at once. Matches
+  with the same attribute name are shown once, annotated with the sources
+  they were found in.
+    [default: {DEFAULT_MERGE}]
+    [possible values: true, false]
+
+NIX_PACKAGE_SEARCH_OUTPUT
+  Output format of matches. 'json' prints a single JSON array, 'ndjson' one
+  JSON object per line. Both skip coloring and padding.
+    [default: {DEFAULT_OUTPUT}]
+    [possible values: text, json, ndjson]
+
 NIX_PACKAGE_SEARCH_PRINT_SEPARATOR
   Separate matches with a newline?
     [default: {DEFAULT_PRINT_SEPARATOR}]
     [possible values: true, false]
 
+NIX_PACKAGE_SEARCH_MAX_CACHE_AGE
+  Consider the cache stale if it is older than this. Use a number followed by
+  a unit: s(econds), m(inutes), h(ours), d(ays) or w(eeks).
+  Leave unset to never consider the cache stale.
+    [default: unset]
+    [possible values: e.g. 12h, 7d, 2w]
+
+NIX_PACKAGE_SEARCH_STALE_CACHE
+  What to do if the cache is older than NIX_PACKAGE_SEARCH_MAX_CACHE_AGE
+  warn: Search the stale cache and print a warning
+  refresh: Refresh the cache first, then search
+  background: Search the stale cache and refresh it in the background
+    [default: {DEFAULT_STALE_CACHE}]
+    [possible values: warn, refresh, background]
+
+NIX_PACKAGE_SEARCH_VERSION_CONSTRAINT
+  Only show packages with versions matching all constraints. Operators are
+  <, <=, =, !=, >= and >, versions are compared like
+  `builtins.compareVersions` does. Separate constraints with commas.
+    [default: unset]
+    [possible values: e.g. >=3.11, >=3.11,<3.13]
+
+NIX_PACKAGE_SEARCH_LICENSE
+  Only show packages with one of these licenses, given by SPDX identifier or
+  by name. Case is ignored. Separate multiple licenses with commas.
+    [default: unset]
+    [possible values: e.g. mit, mit,apache-2.0]
+
+NIX_PACKAGE_SEARCH_PLATFORM
+  Only show packages available on this platform
+    [default: unset]
+    [possible values: e.g. x86_64-linux, aarch64-darwin]
+
+NIX_PACKAGE_SEARCH_NO_UNFREE
+  Hide packages with unfree licenses?
+    [default: {DEFAULT_NO_UNFREE}]
+    [possible values: true, false]
+
+NIX_PACKAGE_SEARCH_NO_BROKEN
+  Hide packages marked as broken?
+    [default: {DEFAULT_NO_BROKEN}]
+    [possible values: true, false]
+
+NIX_PACKAGE_SEARCH_NO_INSECURE
+  Hide packages marked as insecure?
+    [default: {DEFAULT_NO_INSECURE}]
+    [possible values: true, false]
+
+NIX_PACKAGE_SEARCH_SORT
+  Sort matches of the same match type by
+  name: Package name, alphabetically
+  version: Package version, newest first
+    [default: {DEFAULT_SORT}]
+    [possible values: name, version]
+
 NIX_PACKAGE_SEARCH_QUIET
   Suppress non-debug messages?
     [default: {DEFAULT_QUIET}]
@@ -315,6 +709,37 @@ enum ColumnsChoice {
     Description,
 }
 
+/// Output format options
+#[derive(Clone, Debug, ValueEnum)]
+enum OutputChoice {
+    /// Padded and colored columns
+    Text,
+    /// JSON array of matches
+    Json,
+    /// One JSON object per match and line
+    Ndjson,
+}
+
+/// Sort orders of matches with the same match type
+#[derive(Clone, Debug, PartialEq, ValueEnum)]
+enum SortChoice {
+    /// Package name, alphabetically
+    Name,
+    /// Package version, newest first
+    Version,
+}
+
+/// Actions for stale caches
+#[derive(Clone, Debug, ValueEnum)]
+enum StaleCacheChoice {
+    /// Search the stale cache and print a warning
+    Warn,
+    /// Refresh the cache first, then search
+    Refresh,
+    /// Search the stale cache and refresh it in the background
+    Background,
+}
+
 /// Allowed values for coloring output.
 #[derive(Debug, Clone, ValueEnum)]
 enum Colors {
@@ -328,37 +753,133 @@ enum Colors {
     White,
 }
 
-/// Format to parse JSON package info into
-#[derive(Debug, Deserialize)]
-struct Package {
-    // we are not using `pname`
-    version: String,
-    description: String,
+/// Format to serialize the metadata of a single package into
+#[derive(Debug, Serialize)]
+struct PackageInfo<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flake: Option<&'a str>,
+    version: &'a str,
+    description: &'a str,
+    #[serde(flatten)]
+    meta: Option<PackageMeta>,
+}
+
+/// What to do with the packages named by the search terms
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Run,
+    Shell,
+    Install,
+}
+
+/// State of a search that isn't set on the command line
+///
+/// It's filled in while preparing the caches, and passed next to `Cli`.
+#[derive(Debug, Default)]
+struct SearchContext {
+    /// Cache folders to read from, keyed by source, if they aren't the own one
+    cache_folders: HashMap<Option<String>, PathBuf>,
+    /// Names of packages passing the metadata filters, if there are any
+    allowed_packages: Option<HashSet<String>>,
+    /// Match types of packages providing `bin`, keyed by package name
+    bin_matches: HashMap<String, MatchKind>,
+}
+
+/// Package found by `search`, formatted only when printing
+#[derive(Clone, Debug, PartialEq)]
+struct SearchMatch {
+    /// Name as in search results, see `display_name`
+    package: Match,
+    /// Sources of merged matches, see `merge_matches`
+    sources: Option<Vec<String>>,
+}
+
+/// Format to serialize matches into for JSON output
+#[derive(Debug, Serialize)]
+struct JsonMatch<'a> {
+    name: &'a str,
+    attr_path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flake: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sources: Option<Vec<&'a str>>,
+    version: &'a str,
+    description: &'a str,
+    match_type: MatchKind,
 }
 
 /// Defines possible default settings.
 struct Defaults<'a> {
+    any: bool,
     cache_folder: &'a str,
+    system_cache_folder: &'a str,
+    legacy_cache_folder: &'a str,
     cache_file: &'a str,
     experimental: bool,
     experimental_cache_file: &'a str,
+    fixed_strings: bool,
+    flake: &'a str,
     color_mode: clap::ColorChoice,
     columns: ColumnsChoice,
     flip: bool,
+    fuzzy: bool,
     ignore_case: bool,
+    merge: bool,
+    no_broken: bool,
+    no_insecure: bool,
+    no_unfree: bool,
+    output: OutputChoice,
     print_separator: bool,
     quiet: bool,
+    sort: SortChoice,
+    stale_cache: StaleCacheChoice,
 
     exact_color: Colors,
     direct_color: Colors,
     indirect_color: Colors,
 }
 
-/// Print messages if quiet==false
-fn message(message_string: &str, quiet: bool) -> Result<(), Box<dyn Error>> {
+/// Flake references to search, empty when only searching channels
+///
+/// Empty references are skipped, so `--flake=` clears the flakes of the
+/// environment and config file.
+fn flakes(cli: &Cli) -> Vec<&str> {
+    let flakes: Vec<&str> = cli
+        .flake
+        .iter()
+        .map(String::as_str)
+        .filter(|flake| !flake.is_empty())
+        .collect();
+    match (flakes.is_empty(), cli.experimental || cli.merge) {
+        (false, _) => flakes,
+        (true, true) => vec![DEFAULTS.flake],
+        (true, false) => vec![],
+    }
+}
+
+/// Sources to search, channels are represented by `None`
+fn sources(cli: &Cli) -> Vec<Option<&str>> {
+    let flakes = flakes(cli).into_iter().map(Some);
+    match cli.merge || flakes.len() == 0 {
+        true => std::iter::once(None).chain(flakes).collect(),
+        false => flakes.collect(),
+    }
+}
+
+/// Seconds since the UNIX epoch
+fn unix_timestamp(time: SystemTime) -> Result<u64, Box<dyn Error>> {
+    Ok(time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| format!("Can't get timestamp: {err}"))?
+        .as_secs())
+}
+
+/// Print notices to stderr if quiet==false, stdout is kept for results
+fn notice(message_string: &str, quiet: bool) -> Result<(), Box<dyn Error>> {
     if !quiet {
-        writeln!(io::stdout(), "{}", message_string)
-            .map_err(|err| format!("Can't write to stdout: {err}"))?;
+        writeln!(io::stderr(), "{}", message_string)
+            .map_err(|err| format!("Can't write to stderr: {err}"))?;
     }
     Ok(())
 }
@@ -375,16 +896,22 @@ fn styles() -> Styles {
 /// Replace template items in long help text with default settings.
 fn option_help_text(help_text: &str) -> String {
     help_text
+        .replace("{DEFAULT_ANY}", &DEFAULTS.any.to_string())
         .replace("{DEFAULT_EXPERIMENTAL}", &DEFAULTS.experimental.to_string())
         .replace(
             "{DEFAULT_CACHE_FOLDER}",
-            &home::home_dir()
-                .unwrap() // We previously made sure this works.
-                .join(DEFAULTS.cache_folder)
-                .display()
-                .to_string(),
+            &default_cache_folder().display().to_string(),
+        )
+        .replace(
+            "{DEFAULT_SYSTEM_CACHE_FOLDER}",
+            DEFAULTS.system_cache_folder,
         )
         .replace("{DEFAULT_CACHE_FILE}", DEFAULTS.cache_file)
+        .replace("{DEFAULT_FLAKE}", DEFAULTS.flake)
+        .replace(
+            "{DEFAULT_FIXED_STRINGS}",
+            &DEFAULTS.fixed_strings.to_string(),
+        )
         .replace(
             "{DEFAULT_EXPERIMENTAL_CACHE_FILE}",
             DEFAULTS.experimental_cache_file,
@@ -398,12 +925,29 @@ fn option_help_text(help_text: &str) -> String {
             &format!("{:?}", DEFAULTS.columns).to_lowercase(),
         )
         .replace("{DEFAULT_FLIP}", &DEFAULTS.flip.to_string())
+        .replace("{DEFAULT_FUZZY}", &DEFAULTS.fuzzy.to_string())
         .replace("{DEFAULT_IGNORE_CASE}", &DEFAULTS.ignore_case.to_string())
+        .replace("{DEFAULT_MERGE}", &DEFAULTS.merge.to_string())
+        .replace("{DEFAULT_NO_BROKEN}", &DEFAULTS.no_broken.to_string())
+        .replace("{DEFAULT_NO_INSECURE}", &DEFAULTS.no_insecure.to_string())
+        .replace("{DEFAULT_NO_UNFREE}", &DEFAULTS.no_unfree.to_string())
+        .replace(
+            "{DEFAULT_OUTPUT}",
+            &format!("{:?}", DEFAULTS.output).to_lowercase(),
+        )
         .replace(
             "{DEFAULT_PRINT_SEPARATOR}",
             &DEFAULTS.print_separator.to_string(),
         )
         .replace("{DEFAULT_QUIET}", &DEFAULTS.quiet.to_string())
+        .replace(
+            "{DEFAULT_SORT}",
+            &format!("{:?}", DEFAULTS.sort).to_lowercase(),
+        )
+        .replace(
+            "{DEFAULT_STALE_CACHE}",
+            &format!("{:?}", DEFAULTS.stale_cache).to_lowercase(),
+        )
         .replace(
             "{DEFAULT_EXACT_COLOR}",
             &format!("{:?}", DEFAULTS.exact_color).to_lowercase(),
@@ -418,35 +962,98 @@ fn option_help_text(help_text: &str) -> String {
         )
 }
 
-/// Find matches from cache file
-fn get_matches(cli: &Cli, content: &str) -> Result<String, Box<dyn Error>> {
-    let search_term = cli
-        .search_term
-        .as_ref()
-        .ok_or("Can't get search term as ref")?;
+/// Parse a cache age like `7d` to seconds
+fn parse_cache_age(age: &str) -> Result<u64, String> {
+    let age = age.trim();
+    let split_at = age
+        .find(|character: char| !character.is_ascii_digit())
+        .ok_or(format!("Missing unit in '{age}', use one of s, m, h, d, w"))?;
+    let (number, unit) = age.split_at(split_at);
+    let number: u64 = number
+        .parse()
+        .map_err(|err| format!("Can't parse number in '{age}': {err}"))?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown unit '{unit}', use one of s, m, h, d, w")),
+    };
+    number
+        .checked_mul(unit_seconds)
+        .ok_or(format!("Cache age '{age}' is too large"))
+}
+
+/// Format a number of seconds to a human readable age, rounded down
+fn format_age(seconds: u64) -> String {
+    let (number, unit) = match seconds {
+        0..60 => (seconds, "second"),
+        60..3_600 => (seconds / 60, "minute"),
+        3_600..86_400 => (seconds / 3_600, "hour"),
+        _ => (seconds / 86_400, "day"),
+    };
+    match number {
+        1 => format!("{number} {unit}"),
+        _ => format!("{number} {unit}s"),
+    }
+}
 
-    // Matcher to find search term in rows
-    let matcher = RegexMatcherBuilder::new()
-        .case_insensitive(cli.ignore_case)
-        .build(search_term)
-        .map_err(|err| format!("Can't build regex: {err}"))?;
-    // Printer collects matching rows in a Vec
-    let mut printer = Standard::new_no_color(vec![]);
+/// Age of the cache in seconds, if it is older than `max_cache_age`
+fn stale_cache_age(
+    header: &CacheHeader,
+    max_cache_age: Option<u64>,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    let Some(max_cache_age) = max_cache_age else {
+        return Ok(None);
+    };
+    // Clocks can go backwards, treat caches from the future as fresh
+    let age = unix_timestamp(SystemTime::now())?.saturating_sub(header.created);
+    match age > max_cache_age {
+        true => Ok(Some(age)),
+        false => Ok(None),
+    }
+}
 
-    // Execute search and collect output
-    SearcherBuilder::new()
-        .line_number(false)
-        .build()
-        .search_slice(&matcher, content.as_bytes(), printer.sink(&matcher))
-        .map_err(|err| format!("Can't build searcher: {err}"))?;
+/// Refresh the cache in a detached process, so the current search isn't blocked
+///
+/// Only the stale cache is refreshed, settings of the environment and config
+/// file that pick other sources are overridden.
+fn spawn_background_refresh(cli: &Cli, flake: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let executable =
+        std::env::current_exe().map_err(|err| format!("Can't find `nps` executable: {err}"))?;
+
+    let mut command = Command::new(executable);
+    command
+        .arg("--refresh")
+        .arg(format!(
+            "--cache-folder={}",
+            own_cache_folder(cli).display()
+        ))
+        .arg(format!("--experimental={}", flake.is_some()))
+        .arg(format!("--flake={}", flake.unwrap_or_default()))
+        .arg("--merge=false")
+        .arg("--quiet=true")
+        .env_remove(format!("{ENV_PREFIX}FLAKES"))
+        .env_remove(format!("{ENV_PREFIX}MERGE"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // Own process group, so closing the terminal doesn't stop the refresh
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
 
-    // into_inner gives us back the underlying writer we provided to
-    // new_no_color, which is wrapped in a termcolor::NoColor. Thus, a second
-    // into_inner gives us back the actual buffer.
-    let output = String::from_utf8(printer.into_inner().into_inner())
-        .map_err(|err| format!("Can't parse printer string: {err}"))?;
+    // The refresh outlives us, so we don't wait for it
+    #[allow(clippy::zombie_processes)]
+    let child = command
+        .spawn()
+        .map_err(|err| format!("Can't spawn background refresh: {err}"))?;
+    log::debug!("spawned background refresh with pid {}", child.id());
 
-    Ok(output)
+    Ok(())
 }
 
 /// Case converter for case-insensitive searches
@@ -457,805 +1064,2933 @@ fn convert_case(string: &str, ignore_case: bool) -> String {
     }
 }
 
-type MatchVecs = (Vec<String>, Vec<String>, Vec<String>);
-
-/// Sort matches into match types and pad the lines to aligned columns
-fn sort_and_pad_matches(cli: &Cli, raw_matches: String) -> Result<MatchVecs, Box<dyn Error>> {
-    let search_term = cli
-        .search_term
-        .as_ref()
-        .ok_or("Can't get search term as ref")?;
-
-    let mut name_lengths: Vec<usize> = vec![];
-    let mut version_lengths: Vec<usize> = vec![];
+/// Compile the search terms
+fn build_query(cli: &Cli, terms: &[QueryTerm]) -> Result<Query, Box<dyn Error>> {
+    Query::new(
+        terms.to_vec(),
+        QueryOptions {
+            any: cli.any,
+            ignore_case: cli.ignore_case,
+            fixed_strings: cli.fixed_strings,
+        },
+    )
+}
 
-    for line in raw_matches.lines() {
-        let split_line: Vec<&str> = line.splitn(3, ' ').collect();
+/// Package name in the cache of channels (`None`) or a flake, without
+/// channel name
+fn plain_name<'a>(source: Option<&str>, name: &'a str) -> &'a str {
+    match source {
+        Some(_) => name,
+        None => name
+            .split_once('.')
+            .map(|(_channel, name)| name)
+            .unwrap_or(name),
+    }
+}
 
-        // Try to get a split_line element: `.get()`,
-        // use &"" if missing: `.unwrap_or(&"")`,
-        // and append lengths `.len()` to *_lengths vectors.
-        #[allow(clippy::get_first)]
-        name_lengths.push(split_line.get(0).unwrap_or(&"").len());
-        version_lengths.push(split_line.get(1).unwrap_or(&"").len());
+/// Optimal string alignment distances of `term` to `name` and to the closest
+/// prefix of `name`
+///
+/// Like the Levenshtein distance, but swapping two adjacent characters counts
+/// as a single edit, so `neovmi` is only one edit away from `neovim`.
+fn edit_distances(term: &[char], name: &[char]) -> (usize, usize) {
+    // distances[i][j]: edits to get from term[..i] to name[..j]
+    let mut distances = vec![vec![0; name.len() + 1]; term.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
     }
 
-    // Mininum cell size will be the largest contained string
-    let name_padding = *name_lengths.iter().max().unwrap_or(&0);
-    let version_padding = *version_lengths.iter().max().unwrap_or(&0);
+    for i in 1..=term.len() {
+        for j in 1..=name.len() {
+            let substitution_cost = usize::from(term[i - 1] != name[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + substitution_cost);
+            if i > 1 && j > 1 && term[i - 1] == name[j - 2] && term[i - 2] == name[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
 
-    let mut padded_matches_exact: Vec<String> = vec![];
-    let mut padded_matches_direct: Vec<String> = vec![];
-    let mut padded_matches_indirect: Vec<String> = vec![];
+    let last_row = &distances[term.len()];
+    let distance = last_row[name.len()];
+    let prefix_distance = *last_row.iter().min().unwrap_or(&distance);
+    (distance, prefix_distance)
+}
 
-    for line in raw_matches.lines() {
-        let split_line: Vec<&str> = line.splitn(3, ' ').collect();
+/// Check if all characters of `term` appear in `name`, in order
+fn is_subsequence(term: &[char], name: &[char]) -> bool {
+    let mut name_chars = name.iter();
+    term.iter()
+        .all(|term_char| name_chars.any(|name_char| name_char == term_char))
+}
 
-        #[allow(clippy::get_first)] // supress clippy warning for this block
-        let name = split_line.get(0).unwrap_or(&"");
-        let version = split_line.get(1).unwrap_or(&"");
-        let description = split_line.get(2).unwrap_or(&"");
+/// Match type and score of a fuzzy match, lower scores are better
+///
+/// Returns `None` if the package name doesn't match at all.
+fn fuzzy_match(cli: &Cli, search_term: &str, name: &str) -> Option<(MatchKind, usize)> {
+    let term: Vec<char> = convert_case(search_term, cli.ignore_case).chars().collect();
+    let name: Vec<char> = convert_case(name, cli.ignore_case).chars().collect();
+
+    // Allow one typo per four characters, but at least one
+    let max_distance = (term.len() / 4).max(1);
+
+    let (distance, prefix_distance) = edit_distances(&term, &name);
+    if distance <= max_distance {
+        Some((MatchKind::Exact, distance))
+    } else if prefix_distance <= max_distance {
+        Some((MatchKind::Direct, prefix_distance))
+    } else if is_subsequence(&term, &name) {
+        Some((MatchKind::Indirect, name.len() - term.len()))
+    } else {
+        None
+    }
+}
 
-        let assembled_line = match &cli.columns {
-            ColumnsChoice::All => format!(
-                "{:name_padding$}  {:version_padding$}  {}",
-                name, version, description
-            ),
-            ColumnsChoice::Version => format!("{:name_padding$}  {}", name, version),
-            ColumnsChoice::Description => format!("{:name_padding$}  {}", name, description),
-            ColumnsChoice::None => format!("{} ", name),
-        };
+/// Find matches in the cache of one source, best ones first
+///
+/// Lines need to match all search terms, or any of them with `--any`. Fuzzy
+/// matches are sorted by match type and score, the primary search term is
+/// matched fuzzily and the others as usual.
+fn find_matches(
+    cli: &Cli,
+    terms: &[QueryTerm],
+    source: Option<&str>,
+    cache: &Cache,
+) -> Result<Vec<Match>, Box<dyn Error>> {
+    let query = build_query(cli, terms)?;
+    if !cli.fuzzy {
+        return nps::search(cache, &query);
+    }
 
-        // Handle case-insensitive, if requested
-        let converted_search_term = &convert_case(search_term, cli.ignore_case);
-        let converted_name = &convert_case(name, cli.ignore_case);
-
-        // Package names from channels are prepended with "nixos." or "nixpgks."
-        match cli.experimental {
-            true => {
-                if converted_name == converted_search_term {
-                    padded_matches_exact.push(assembled_line);
-                } else if converted_name.starts_with(converted_search_term) {
-                    padded_matches_direct.push(assembled_line);
-                } else {
-                    padded_matches_indirect.push(assembled_line);
-                }
-            }
-            false => {
-                if converted_name == &("nixos.".to_owned() + converted_search_term)
-                    || converted_name == &("nixpkgs.".to_owned() + converted_search_term)
-                {
-                    padded_matches_exact.push(assembled_line);
-                } else if converted_name.starts_with(&("nixos.".to_owned() + converted_search_term))
-                    || converted_name.starts_with(&("nixpkgs.".to_owned() + converted_search_term))
-                {
-                    padded_matches_direct.push(assembled_line);
-                } else {
-                    padded_matches_indirect.push(assembled_line);
-                }
-            }
+    let primary_term = query.primary_term().ok_or("Can't get search term")?;
+    let other_terms = query
+        .terms()
+        .iter()
+        .filter(|term| !std::ptr::eq(*term, primary_term))
+        .cloned()
+        .collect();
+    let mut matches = vec![];
+    for mut found in nps::search(cache, &Query::new(other_terms, query.options())?)? {
+        let name = plain_name(source, &found.name);
+        if let Some((match_type, score)) = fuzzy_match(cli, &primary_term.pattern, name) {
+            found.kind = match_type;
+            matches.push((score, found));
         }
     }
+    // Stable sort, keep the cache order for equal scores
+    matches.sort_by_key(|(score, found)| (found.kind, *score));
 
-    Ok((
-        padded_matches_exact,
-        padded_matches_direct,
-        padded_matches_indirect,
-    ))
+    Ok(matches.into_iter().map(|(_score, found)| found).collect())
 }
 
-/// Color the search term in different match types
-fn color_matches(
+/// Package names close to the search term, best first
+///
+/// Names are ranked by their edit distance to the search term, ties are broken
+/// by the length of the shared attribute path prefix, so
+/// `python3Packages.reqests` suggests `python3Packages.requests` before
+/// `python2Packages.requests`.
+fn suggestions<'a>(
     cli: &Cli,
-    sorted_padded_matches: MatchVecs,
-    color_choice: termcolor::ColorChoice,
-) -> Result<[Buffer; 3], Box<dyn Error>> {
-    let (mut padded_matches_exact, mut padded_matches_direct, mut padded_matches_indirect) =
-        sorted_padded_matches;
-    let search_term = cli
-        .search_term
-        .as_ref()
-        .ok_or("Can't get search term as ref")?;
-
-    // Defining different colors for different match types
-    let exact_color: UserColorSpec = format!("match:fg:{:?}", &cli.exact_color).parse()?;
-    let direct_color: UserColorSpec = format!("match:fg:{:?}", &cli.direct_color).parse()?;
-    let indirect_color: UserColorSpec = format!("match:fg:{:?}", &cli.indirect_color).parse()?;
-
-    // Font styles for match types
-    let exact_style: UserColorSpec = "match:style:bold".parse()?;
-    let direct_style: UserColorSpec = "match:style:bold".parse()?;
-    let indirect_style: UserColorSpec = "match:style:bold".parse()?;
-
-    // Combining colors and styles to ColorSpecs
-    let exact_color_specs = ColorSpecs::new(&[exact_color, exact_style]);
-    let direct_color_specs = ColorSpecs::new(&[direct_color, direct_style]);
-    let indirect_color_specs = ColorSpecs::new(&[indirect_color, indirect_style]);
+    contents: &'a [(Option<&str>, Cache)],
+) -> Result<Vec<&'a str>, Box<dyn Error>> {
+    let query = build_query(cli, &cli.search_term)?;
+    let search_term = &query.primary_term().ok_or("Can't get search term")?.pattern;
+
+    let term: Vec<char> = convert_case(search_term, cli.ignore_case).chars().collect();
+    // Allow one typo per three characters, but at least two
+    let max_distance = (term.len() / 3).max(2);
+
+    let mut candidates: Vec<(usize, usize, &str)> = vec![];
+    for (source, cache) in contents {
+        for line in cache.body.lines() {
+            let name = plain_name(*source, line.split('\t').next().unwrap_or(""));
+            let name_chars: Vec<char> = convert_case(name, cli.ignore_case).chars().collect();
+            // Cheap check first, the distance is at least the length difference
+            if name_chars.len().abs_diff(term.len()) > max_distance {
+                continue;
+            }
 
-    // Create buffers to write colored output into
-    let bufwtr = BufferWriter::stdout(color_choice);
-    let mut exact_buffer = bufwtr.buffer();
-    let mut direct_buffer = bufwtr.buffer();
-    let mut indirect_buffer = bufwtr.buffer();
-
-    // Printers print to the above buffers
-    let mut exact_printer = StandardBuilder::new()
-        .color_specs(exact_color_specs)
-        .build(&mut exact_buffer);
-    let mut direct_printer = StandardBuilder::new()
-        .color_specs(direct_color_specs)
-        .build(&mut direct_buffer);
-    let mut indirect_printer = StandardBuilder::new()
-        .color_specs(indirect_color_specs)
-        .build(&mut indirect_buffer);
-
-    // Matcher to color `search_term`
-    let matcher = RegexMatcherBuilder::new()
-        .case_insensitive(cli.ignore_case)
-        .build(search_term)
-        .map_err(|err| format!("Can't build regex: {err}"))?;
-
-    // Matcher to find _everything_, so lines without matches are still printed.
-    // This can happen if certain columns are missing.
-    let matcher_all = RegexMatcherBuilder::new().build(".*")?;
+            let (distance, _prefix_distance) = edit_distances(&term, &name_chars);
+            if distance > max_distance {
+                continue;
+            }
+            let shared_prefix = term
+                .iter()
+                .zip(name_chars.iter())
+                .take_while(|(term_char, name_char)| term_char == name_char)
+                .count();
+            candidates.push((distance, shared_prefix, name));
+        }
+    }
 
-    // Let's have the top results at the bottom by default
-    if !cli.flip {
-        padded_matches_exact.reverse();
-        padded_matches_direct.reverse();
-        padded_matches_indirect.reverse();
-    }
-
-    // Coloring and printing to buffers
-    SearcherBuilder::new()
-        .line_number(false)
-        .build()
-        .search_slice(
-            &matcher_all,
-            padded_matches_exact.join("\n").as_bytes(),
-            exact_printer.sink(&matcher),
-        )
-        .map_err(|err| format!("Can't build searcher: {err}"))?;
-    SearcherBuilder::new()
-        .line_number(false)
-        .build()
-        .search_slice(
-            &matcher_all,
-            padded_matches_direct.join("\n").as_bytes(),
-            direct_printer.sink(&matcher),
-        )
-        .map_err(|err| format!("Can't build searcher: {err}"))?;
-    SearcherBuilder::new()
-        .line_number(false)
-        .build()
-        .search_slice(
-            &matcher_all,
-            padded_matches_indirect.join("\n").as_bytes(),
-            indirect_printer.sink(&matcher),
-        )
-        .map_err(|err| format!("Can't build searcher: {err}"))?;
+    candidates.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)).then(a.2.cmp(b.2)));
 
-    Ok([exact_buffer, direct_buffer, indirect_buffer])
+    let mut names: Vec<&str> = vec![];
+    for (_distance, _shared_prefix, name) in candidates {
+        // Channels and flakes may contain the same package
+        if !names.contains(&name) {
+            names.push(name);
+        }
+        if names.len() == MAX_SUGGESTIONS {
+            break;
+        }
+    }
+    Ok(names)
 }
 
-/// Print matches to screen in correct ordering
-fn print_matches(cli: &Cli, colored_matches: [Buffer; 3]) -> Result<(), Box<dyn Error>> {
-    // Assemble match type string segments
-    let mut out: Vec<String> = vec![];
-    for buffer in colored_matches.into_iter() {
-        let content = String::from_utf8(buffer.into_inner())
+/// Package name as it appears in search results
+///
+/// Merging strips the channel name, searching several flakes prefixes the
+/// flake reference.
+fn display_name(cli: &Cli, source: Option<&str>, sources_len: usize, name: &str) -> String {
+    match (cli.merge, source) {
+        (true, None) => name
+            .split_once('.')
+            .map(|(_channel, name)| name)
+            .unwrap_or(name)
+            .to_string(),
+        (false, Some(flake)) if sources_len >= 2 => format!("{flake}#{name}"),
+        _ => name.to_string(),
+    }
+}
+
+/// Rename a match to its name in search results
+///
+/// Display names add or strip a prefix, name highlights are moved along.
+fn rename_match(package: &mut Match, name: String) {
+    let (stripped, added) = match name.len() >= package.name.len() {
+        true => (0, name.len() - package.name.len()),
+        false => (package.name.len() - name.len(), 0),
+    };
+    package.highlights.name = package
+        .highlights
+        .name
+        .iter()
+        .filter(|range| range.end > stripped)
+        .map(|range| range.start.max(stripped) - stripped + added..range.end - stripped + added)
+        .collect();
+    package.name = name;
+}
+
+/// Match type of a package providing the executable `bin`
+///
+/// Like `nix run`, packages without main program are assumed to provide an
+/// executable named like their `pname`.
+fn bin_match_type(bin: &str, meta: &PackageMeta, ignore_case: bool) -> Option<MatchKind> {
+    let bin = convert_case(bin, ignore_case);
+    let main_programs: Vec<String> = meta
+        .main_programs
+        .iter()
+        .map(|program| convert_case(program, ignore_case))
+        .collect();
+    let pname = meta
+        .pname
+        .as_ref()
+        .map(|pname| convert_case(pname, ignore_case));
+
+    if main_programs.contains(&bin) {
+        Some(MatchKind::Exact)
+    } else if main_programs.is_empty() && pname.as_ref() == Some(&bin) {
+        Some(MatchKind::Direct)
+    } else if main_programs
+        .iter()
+        .chain(pname.iter())
+        .any(|name| name.contains(&bin))
+    {
+        Some(MatchKind::Indirect)
+    } else {
+        None
+    }
+}
+
+/// Package metadata of all sources, keyed by package name in search results
+fn display_meta(
+    cli: &Cli,
+    contents: &[(Option<&str>, Cache)],
+) -> Result<HashMap<String, PackageMeta>, Box<dyn Error>> {
+    let mut display_meta = HashMap::new();
+    for (source, cache) in contents {
+        for (name, package_meta) in cache.meta()?.into_iter() {
+            display_meta.insert(
+                display_name(cli, *source, contents.len(), &name),
+                package_meta,
+            );
+        }
+    }
+    Ok(display_meta)
+}
+
+/// Match types of all packages providing the executable `--bin`, keyed by
+/// their name in search results
+fn bin_matches(
+    cli: &Cli,
+    meta: &HashMap<String, PackageMeta>,
+) -> Result<HashMap<String, MatchKind>, Box<dyn Error>> {
+    let bin = cli.bin.as_ref().ok_or("Can't get executable name")?;
+    let mut matches = HashMap::new();
+    for (name, package_meta) in meta.iter() {
+        if let Some(match_type) = bin_match_type(bin, package_meta, cli.ignore_case) {
+            matches.insert(name.to_string(), match_type);
+        }
+    }
+    Ok(matches)
+}
+
+/// Are any of the metadata filters set?
+fn has_filters(cli: &Cli) -> bool {
+    !cli.license.is_empty()
+        || cli.platform.is_some()
+        || cli.no_unfree
+        || cli.no_broken
+        || cli.no_insecure
+}
+
+/// Does a package pass all metadata filters?
+///
+/// Packages whose metadata wasn't evaluated never pass.
+fn passes_filters(cli: &Cli, meta: &PackageMeta) -> bool {
+    let license_ok = cli.license.is_empty()
+        || meta.licenses.iter().any(|license| {
+            cli.license
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(license))
+        });
+    let platform_ok = cli
+        .platform
+        .as_ref()
+        .is_none_or(|platform| meta.platforms.contains(platform));
+
+    meta.evaluated
+        && license_ok
+        && platform_ok
+        && !(cli.no_unfree && meta.unfree)
+        && !(cli.no_broken && meta.broken)
+        && !(cli.no_insecure && meta.insecure)
+}
+
+/// Names of packages passing all metadata filters
+///
+/// Packages without metadata or whose metadata couldn't be evaluated don't
+/// pass, we can't tell if they qualify.
+fn allowed_packages(cli: &Cli, meta: &HashMap<String, PackageMeta>) -> HashSet<String> {
+    meta.iter()
+        .filter(|(_name, package_meta)| passes_filters(cli, package_meta))
+        .map(|(name, _package_meta)| name.to_string())
+        .collect()
+}
+
+/// Find matches in the caches of all sources and combine them
+///
+/// Merges matches by name with `--merge`, otherwise prefixes them with their
+/// flake reference if there are several flakes.
+fn search(
+    cli: &Cli,
+    context: &SearchContext,
+    terms: &[QueryTerm],
+    contents: &[(Option<&str>, Cache)],
+) -> Result<Vec<SearchMatch>, Box<dyn Error>> {
+    let mut source_matches = vec![];
+    for (source, cache) in contents {
+        source_matches.push((*source, find_matches(cli, terms, *source, cache)?));
+    }
+
+    let mut matches = match cli.merge {
+        true => merge_matches(source_matches),
+        false => {
+            let mut matches = vec![];
+            for (source, packages) in source_matches {
+                for mut package in packages {
+                    // Tell apart packages with the same name from different flakes
+                    let name = display_name(cli, source, contents.len(), &package.name);
+                    rename_match(&mut package, name);
+                    matches.push(SearchMatch {
+                        package,
+                        sources: None,
+                    });
+                }
+            }
+            matches
+        }
+    };
+
+    // Drop packages not passing the metadata filters
+    if let Some(allowed_packages) = &context.allowed_packages {
+        matches.retain(|found| allowed_packages.contains(&found.package.name));
+    }
+
+    // Drop packages not matching the version constraints
+    matches.retain(|found| {
+        cli.version_constraint
+            .iter()
+            .all(|constraint| constraint.is_match(&found.package.version))
+    });
+
+    // Only keep packages providing the executable, ranked by how they provide it
+    if cli.bin.is_some() {
+        matches.retain_mut(|found| match context.bin_matches.get(&found.package.name) {
+            Some(match_type) => {
+                found.package.kind = *match_type;
+                true
+            }
+            None => false,
+        });
+    }
+
+    // Highlight whole names of fuzzy matches and of packages providing
+    // `--bin`, they don't need to contain the search term
+    if cli.fuzzy || cli.bin.is_some() {
+        for found in matches.iter_mut() {
+            found.package.highlights.name = std::iter::once(0..found.package.name.len()).collect();
+        }
+    }
+
+    // Newest first, match types are sorted separately afterwards
+    if cli.sort == SortChoice::Version {
+        matches.sort_by(|a, b| compare_versions(&b.package.version, &a.package.version));
+    }
+
+    // Stable sort, keep the order of the search within match types
+    matches.sort_by_key(|found| found.package.kind);
+    Ok(matches)
+}
+
+/// Requested action, if any
+fn action(cli: &Cli) -> Option<Action> {
+    match (cli.run, cli.shell, cli.install) {
+        (true, _, _) => Some(Action::Run),
+        (_, true, _) => Some(Action::Shell),
+        (_, _, true) => Some(Action::Install),
+        _ => None,
+    }
+}
+
+/// Package name, and the flake it comes from, or `None` for channels
+type SourcedPackage<'a> = (Option<&'a str>, String);
+
+/// Find the package exactly matching each search term
+///
+/// Returns the source of every package, and its name with the `nixos.` or
+/// `nixpkgs.` prefix for channels.
+fn exact_packages<'a>(
+    cli: &Cli,
+    contents: &[(Option<&'a str>, Cache)],
+) -> Result<Vec<SourcedPackage<'a>>, Box<dyn Error>> {
+    let mut packages = vec![];
+    for term in cli.search_term.iter() {
+        // Search for every term on its own
+        let terms = std::slice::from_ref(term);
+
+        let mut package = None;
+        for (source, cache) in contents {
+            package = find_matches(cli, terms, *source, cache)?
+                .into_iter()
+                .find(|found| found.kind == MatchKind::Exact)
+                .map(|found| (*source, found.name));
+            if package.is_some() {
+                break;
+            }
+        }
+
+        packages.push(package.ok_or(format!("Can't find a package named \"{term}\""))?);
+    }
+    Ok(packages)
+}
+
+/// Command line of an action on packages
+///
+/// Flakes use `nix run`, `nix shell` and `nix profile install`, channels use
+/// `nix-shell` and `nix-env`.
+fn action_command(
+    action: Action,
+    packages: &[SourcedPackage],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let flake_mode = packages.iter().any(|(source, _name)| source.is_some());
+    // `nix-shell -p` takes attribute names without channel name
+    let attribute = |name: &str| {
+        name.split_once('.')
+            .map(|(_channel, name)| name)
+            .unwrap_or(name)
+            .to_string()
+    };
+    let mut command: Vec<String> = vec![];
+
+    match (action, flake_mode) {
+        (Action::Run, _) if packages.len() != 1 => {
+            return Err("Can't run more than one package at once".into())
+        }
+        (Action::Run, true) => command.extend(["nix".into(), "run".into()]),
+        (Action::Run, false) => {
+            let name = attribute(&packages[0].1);
+            command.extend(["nix-shell".into(), "-p".into(), name.clone()]);
+            command.extend(["--run".into(), name]);
+            return Ok(command);
+        }
+        (Action::Shell, true) => command.extend(["nix".into(), "shell".into()]),
+        (Action::Shell, false) => {
+            command.extend(["nix-shell".into(), "-p".into()]);
+            command.extend(packages.iter().map(|(_source, name)| attribute(name)));
+            return Ok(command);
+        }
+        (Action::Install, true) => {
+            command.extend(["nix".into(), "profile".into(), "install".into()])
+        }
+        (Action::Install, false) => command.extend(["nix-env".into(), "-iA".into()]),
+    }
+
+    for (source, name) in packages {
+        match source {
+            Some(flake) => command.push(format!("{flake}#{name}")),
+            // Mixing channels and flakes, e.g. with --merge
+            None if flake_mode => {
+                return Err(format!("Can't mix channel package {name} with flakes").into())
+            }
+            None => command.push(name.to_string()),
+        }
+    }
+    Ok(command)
+}
+
+/// Ask the user to confirm a command on stderr, defaults to no
+fn confirm(command: &str) -> Result<bool, Box<dyn Error>> {
+    write!(io::stderr(), "Run `{command}`? [y/N] ")
+        .map_err(|err| format!("Can't write to stderr: {err}"))?;
+    io::stderr()
+        .flush()
+        .map_err(|err| format!("Can't flush stderr: {err}"))?;
+
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|err| format!("Can't read answer: {err}"))?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Run an action on the packages named by the search terms
+///
+/// Asks for confirmation first, or only prints the command with `--dry-run`.
+fn run_action(
+    cli: &Cli,
+    action: Action,
+    contents: &[(Option<&str>, Cache)],
+) -> Result<ExitCode, Box<dyn Error>> {
+    let packages = exact_packages(cli, contents)?;
+    let command = action_command(action, &packages)?;
+    let command_line = command.join(" ");
+    log::debug!("action command: {}", command_line);
+
+    if cli.dry_run {
+        writeln!(io::stdout(), "{command_line}")
+            .map_err(|err| format!("Can't write to stdout: {err}"))?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if !confirm(&command_line)? {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .status()
+        .map_err(|err| format!("Can't execute `{}` command: {err}", command[0]))?;
+    // Pass on the exit code, e.g. of `nix run`, codes that don't fit fail plainly
+    Ok(match status.code() {
+        Some(code) => ExitCode::from(u8::try_from(code).unwrap_or(1)),
+        None => ExitCode::FAILURE,
+    })
+}
+
+/// Find the packages named ATTR in all sources, with their metadata
+///
+/// Channel packages can be given with or without the channel name.
+fn package_infos<'a>(
+    cli: &'a Cli,
+    contents: &'a [(Option<&'a str>, Cache)],
+) -> Result<Vec<PackageInfo<'a>>, Box<dyn Error>> {
+    let attr = cli.info.as_deref().ok_or("Can't get package name")?;
+    let mut infos = vec![];
+    for (source, cache) in contents {
+        let mut meta = match cache.meta() {
+            Ok(meta) => meta,
+            Err(err) => {
+                log::warn!("{err}");
+                HashMap::new()
+            }
+        };
+        for line in cache.body.lines() {
+            let split_line: Vec<&str> = line.splitn(3, '\t').collect();
+            #[allow(clippy::get_first)] // supress clippy warning for this block
+            let name = split_line.get(0).unwrap_or(&"");
+            if *name != attr && plain_name(*source, name) != attr {
+                continue;
+            }
+            infos.push(PackageInfo {
+                name,
+                flake: *source,
+                version: split_line.get(1).unwrap_or(&""),
+                description: split_line.get(2).unwrap_or(&""),
+                meta: meta.remove(*name),
+            });
+        }
+    }
+    Ok(infos)
+}
+
+/// Format package metadata as a list of labeled values
+fn format_info(info: &PackageInfo) -> String {
+    let yes_no = |value: bool| if value { "yes" } else { "no" }.to_string();
+    let heading = match info.flake {
+        Some(flake) => format!("{flake}#{}", info.name),
+        None => info.name.to_string(),
+    };
+    let mut fields = vec![
+        ("version", info.version.to_string()),
+        ("description", info.description.to_string()),
+    ];
+    if let Some(meta) = &info.meta {
+        fields.extend([
+            ("main program", meta.main_programs.join(", ")),
+            ("licenses", meta.licenses.join(", ")),
+            ("homepage", meta.homepages.join(", ")),
+            ("platforms", meta.platforms.join(", ")),
+            ("maintainers", meta.maintainers.join(", ")),
+        ]);
+        // Unknown flags are left out rather than shown as "no"
+        if meta.evaluated {
+            fields.extend([
+                ("broken", yes_no(meta.broken)),
+                ("unfree", yes_no(meta.unfree)),
+                ("insecure", yes_no(meta.insecure)),
+            ]);
+        }
+    }
+
+    let mut formatted = heading;
+    for (label, value) in fields
+        .into_iter()
+        .filter(|(_label, value)| !value.is_empty())
+    {
+        formatted.push_str(&format!("\n  {:<14}{}", format!("{label}:"), value));
+    }
+    formatted
+}
+
+/// Print the metadata of the packages named `--info`
+fn show_info(cli: &Cli, contents: &[(Option<&str>, Cache)]) -> Result<(), Box<dyn Error>> {
+    let infos = package_infos(cli, contents)?;
+    if infos.is_empty() {
+        return Err(format!(
+            "No package named {}",
+            cli.info.as_deref().unwrap_or_default()
+        )
+        .into());
+    }
+
+    let output = match cli.output {
+        OutputChoice::Text => infos
+            .iter()
+            .map(format_info)
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+        OutputChoice::Json => serde_json::to_string_pretty(&infos)
+            .map_err(|err| format!("Can't serialize package info: {err}"))?,
+        OutputChoice::Ndjson => infos
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|err| format!("Can't serialize package info: {err}"))?
+            .join("\n"),
+    };
+    writeln!(io::stdout(), "{output}").map_err(|err| format!("Can't write to stdout: {err}"))?;
+    Ok(())
+}
+
+/// Format cache changes as lists of added, removed and updated packages
+fn format_changes(changes: &CacheChanges, now: u64) -> String {
+    let source = changes.flake.unwrap_or("channels");
+    let since = format_age(now.saturating_sub(changes.previous_created));
+    if changes.added.is_empty() && changes.removed.is_empty() && changes.updated.is_empty() {
+        return format!("No changes in {source} since the refresh {since} ago");
+    }
+
+    let name_padding = changes
+        .added
+        .iter()
+        .chain(changes.removed.iter())
+        .chain(changes.updated.iter())
+        .map(|package| package.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut formatted = format!("Changes in {source} since the refresh {since} ago:");
+    for (heading, packages) in [
+        ("Added", &changes.added),
+        ("Removed", &changes.removed),
+        ("Updated", &changes.updated),
+    ] {
+        if packages.is_empty() {
+            continue;
+        }
+        formatted.push_str(&format!("\n{heading} ({}):", packages.len()));
+        for package in packages {
+            let version = match (package.old_version, package.new_version) {
+                (Some(old_version), Some(new_version)) => format!("{old_version} -> {new_version}"),
+                (old_version, new_version) => old_version.or(new_version).unwrap_or("").to_string(),
+            };
+            let line = format!("\n  {:name_padding$}  {}", package.name, version);
+            formatted.push_str(line.trim_end());
+        }
+    }
+    formatted
+}
+
+/// Print the changes of the last refresh of all sources
+fn show_changes(
+    cli: &Cli,
+    context: &SearchContext,
+    sources: &[Option<&str>],
+) -> Result<(), Box<dyn Error>> {
+    let mut caches = vec![];
+    for source in sources {
+        let cache = Cache::load(cache_folder(cli, context, *source), *source)?;
+        let previous = cache.previous()?.ok_or(format!(
+            "No previous cache of {}, changes are listed after the next refresh",
+            source.unwrap_or("channels")
+        ))?;
+        caches.push((cache, previous));
+    }
+
+    let all_changes: Vec<CacheChanges> = caches
+        .iter()
+        .map(|(cache, previous)| cache.changes(previous))
+        .collect();
+
+    let output = match cli.output {
+        OutputChoice::Text => {
+            let now = unix_timestamp(SystemTime::now())?;
+            all_changes
+                .iter()
+                .map(|changes| format_changes(changes, now))
+                .collect::<Vec<String>>()
+                .join("\n\n")
+        }
+        OutputChoice::Json => serde_json::to_string_pretty(&all_changes)
+            .map_err(|err| format!("Can't serialize changes: {err}"))?,
+        OutputChoice::Ndjson => all_changes
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|err| format!("Can't serialize changes: {err}"))?
+            .join("\n"),
+    };
+    writeln!(io::stdout(), "{output}").map_err(|err| format!("Can't write to stdout: {err}"))?;
+    Ok(())
+}
+
+/// Widths of the name, version and sources columns, in characters
+fn column_widths(matches: &[SearchMatch]) -> (usize, usize, usize) {
+    let width = |column_width: fn(&SearchMatch) -> usize| {
+        matches.iter().map(column_width).max().unwrap_or(0)
+    };
+    (
+        width(|found| found.package.name.chars().count()),
+        width(|found| found.package.version.chars().count()),
+        // Sources are wrapped in brackets
+        width(|found| {
+            found
+                .sources
+                .as_ref()
+                .map_or(0, |sources| sources.join(",").chars().count())
+        }) + 2,
+    )
+}
+
+/// Style of the search terms in matches of a match type
+fn highlight_spec(cli: &Cli, match_type: MatchKind) -> Result<ColorSpec, Box<dyn Error>> {
+    let color = match match_type {
+        MatchKind::Exact => &cli.exact_color,
+        MatchKind::Direct => &cli.direct_color,
+        MatchKind::Indirect => &cli.indirect_color,
+    };
+    let mut color_spec = ColorSpec::new();
+    color_spec
+        .set_fg(Some(format!("{color:?}").parse()?))
+        .set_bold(true);
+    Ok(color_spec)
+}
+
+/// Write `text`, highlighting the byte `ranges`
+fn write_highlighted(
+    buffer: &mut Buffer,
+    text: &str,
+    ranges: &[Range<usize>],
+    color_spec: &ColorSpec,
+) -> io::Result<()> {
+    let bytes = text.as_bytes();
+    let mut position = 0;
+    for range in ranges {
+        buffer.write_all(&bytes[position..range.start])?;
+        buffer.set_color(color_spec)?;
+        buffer.write_all(&bytes[range.clone()])?;
+        buffer.reset()?;
+        position = range.end;
+    }
+    buffer.write_all(&bytes[position..])
+}
+
+/// Write a match as a line of aligned columns
+fn write_match(
+    cli: &Cli,
+    buffer: &mut Buffer,
+    found: &SearchMatch,
+    (name_width, version_width, sources_width): (usize, usize, usize),
+    color_spec: &ColorSpec,
+) -> io::Result<()> {
+    let package = &found.package;
+    let sources = found
+        .sources
+        .as_ref()
+        .map(|sources| format!("[{}]", sources.join(",")));
+
+    let name = Some((
+        package.name.as_str(),
+        &package.highlights.name[..],
+        name_width,
+    ));
+    let version = Some((
+        package.version.as_str(),
+        &package.highlights.version[..],
+        version_width,
+    ));
+    let description = Some((
+        package.description.as_str(),
+        &package.highlights.description[..],
+        0,
+    ));
+    // Merged matches are annotated with their sources
+    let sources = sources
+        .as_deref()
+        .map(|sources| (sources, &[][..], sources_width));
+    let cells: Vec<(&str, &[Range<usize>], usize)> = match cli.columns {
+        ColumnsChoice::All => vec![name, version, sources, description],
+        ColumnsChoice::Version => vec![name, version, sources],
+        ColumnsChoice::Description => vec![name, sources, description],
+        ColumnsChoice::None => vec![name, sources],
+    }
+    .into_iter()
+    .flatten()
+    .collect();
+
+    for (position, (text, ranges, width)) in cells.iter().enumerate() {
+        if position > 0 {
+            write!(buffer, "  ")?;
+        }
+        write_highlighted(buffer, text, ranges, color_spec)?;
+        // Pad all columns but the last one
+        if position + 1 < cells.len() {
+            write!(
+                buffer,
+                "{:padding$}",
+                "",
+                padding = width.saturating_sub(text.chars().count())
+            )?;
+        }
+    }
+    // Names without other columns are followed by a space
+    if cells.len() == 1 {
+        write!(buffer, " ")?;
+    }
+    writeln!(buffer)
+}
+
+/// Format matches as aligned columns, highlighting the search terms
+///
+/// Matches are grouped by match type, with the top results at the bottom,
+/// unless `--flip` is set.
+fn format_matches(
+    cli: &Cli,
+    matches: &[SearchMatch],
+    color_choice: termcolor::ColorChoice,
+) -> Result<String, Box<dyn Error>> {
+    let widths = column_widths(matches);
+    let bufwtr = BufferWriter::stdout(color_choice);
+
+    let mut groups: Vec<String> = vec![];
+    for match_type in [MatchKind::Exact, MatchKind::Direct, MatchKind::Indirect] {
+        let color_spec = highlight_spec(cli, match_type)?;
+        let mut matches_of_type: Vec<&SearchMatch> = matches
+            .iter()
+            .filter(|found| found.package.kind == match_type)
+            .collect();
+        // Let's have the top results at the bottom by default
+        if !cli.flip {
+            matches_of_type.reverse();
+        }
+
+        let mut buffer = bufwtr.buffer();
+        for found in matches_of_type {
+            write_match(cli, &mut buffer, found, widths, &color_spec)
+                .map_err(|err| format!("Can't write match: {err}"))?;
+        }
+        let group = String::from_utf8(buffer.into_inner())
             .map_err(|err| format!("Can't get string from buffer: {err}"))?;
-        if !content.is_empty() {
-            out.push(content);
+        if !group.is_empty() {
+            groups.push(group);
         }
     }
 
     if !cli.flip {
-        out.reverse();
+        groups.reverse();
     }
 
     // Use newlines as separators, if requested
     let separator = match cli.separate {
-        true => "\n".to_string(),
-        false => "".to_string(),
+        true => "\n",
+        false => "",
     };
-    // BufferWriter introduces a newline that we need to trim for some reason
-    writeln!(io::stdout(), "{}", &out.join(&separator).trim())
-        .map_err(|err| format!("Can't write to stdout: {err}"))?;
+    Ok(groups.join(separator).trim().to_string())
+}
 
+/// Print matches to screen in correct ordering
+fn print_matches(
+    cli: &Cli,
+    matches: &[SearchMatch],
+    color_choice: termcolor::ColorChoice,
+) -> Result<(), Box<dyn Error>> {
+    let output = format_matches(cli, matches, color_choice)?;
+    writeln!(io::stdout(), "{output}").map_err(|err| format!("Can't write to stdout: {err}"))?;
     Ok(())
 }
 
-/// Parse package info from JSON to (NAME VERSION DESCRIPTION) lines
-fn parse_json_to_lines(raw_output: &str) -> Result<String, Box<dyn Error>> {
-    // Load JSON package info into a HashMap
-    let parsed: HashMap<String, Package> =
-        serde_json::from_str(raw_output).map_err(|err| format!("Can't parse JSON: {err}"))?;
-
-    let mut lines = vec![];
-    for (name_string, package) in parsed.into_iter() {
-        // `name_string` is, for example, "legacyPackages.x86_64-linux.auctex"
-        // Keep everything after the second '.' to get the package "name".
-        // This is different from package.pname, which contains the name
-        // of the executable, which can be different from the package name.
-        let name_vec: Vec<&str> = name_string.splitn(3, '.').collect();
-        let name = name_vec.get(2).ok_or("Can't get package name from JSON.")?;
-        lines.push(format!(
-            "{} {} {}",
-            name, package.version, package.description
-        ));
+/// Merge matches of several sources by name and annotate them with their sources
+///
+/// The first source to contain a name provides version, description and
+/// match type. Sources are labeled `channel:<CHANNEL_NAME>` for channels, or
+/// by their flake reference.
+fn merge_matches(source_matches: Vec<(Option<&str>, Vec<Match>)>) -> Vec<SearchMatch> {
+    let mut merged: Vec<SearchMatch> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (source, packages) in source_matches {
+        for mut package in packages {
+            // Strip the channel name, e.g. "nixos." or "nixpkgs."
+            let (label, name) = match source {
+                None => match package.name.split_once('.') {
+                    Some((channel, name)) => (format!("channel:{channel}"), name.to_string()),
+                    None => ("channel".to_string(), package.name.clone()),
+                },
+                Some(flake) => (flake.to_string(), package.name.clone()),
+            };
+
+            match positions.get(&name) {
+                Some(&position) => {
+                    let labels = merged[position].sources.get_or_insert_with(Vec::new);
+                    if !labels.contains(&label) {
+                        labels.push(label);
+                    }
+                }
+                None => {
+                    positions.insert(name.clone(), merged.len());
+                    rename_match(&mut package, name);
+                    merged.push(SearchMatch {
+                        package,
+                        sources: Some(vec![label]),
+                    });
+                }
+            }
+        }
     }
-    lines.sort();
-    Ok(lines.join("\n"))
+
+    merged.sort_by(|a, b| a.package.name.cmp(&b.package.name));
+    merged
 }
 
-/// Check if flakes are enabled
-fn check_flakes_enabled() -> Result<bool, Box<dyn Error>> {
-    let probe_for_flakes = Command::new("nix")
-        .arg("--extra-experimental-features")
-        .arg("nix-command")
-        .arg("config")
-        .arg("show")
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|err| format!("Can't execute `nix` command: {err}"))?;
-    let find_experimental_features = Command::new("grep")
-        .arg("^experimental-features")
-        .stdin(Stdio::from(probe_for_flakes.stdout.unwrap()))
-        .stdout(Stdio::piped())
-        .spawn()
-        .map_err(|err| format!("Can't execute `grep` command: {err}"))?;
-    let find_flakes = Command::new("grep")
-        .arg("flakes")
-        .stdin(Stdio::from(find_experimental_features.stdout.unwrap()))
-        .stdout(Stdio::piped())
-        .status()
-        .map_err(|err| format!("Can't execute `grep` command: {err}"))?;
+/// Print matches as JSON objects, skipping padding and coloring
+///
+/// Match types are ordered the same way as for `print_matches`.
+fn print_json_matches(cli: &Cli, matches: &[SearchMatch]) -> Result<(), Box<dyn Error>> {
+    let flakes = flakes(cli);
+    let mut json_matches: Vec<JsonMatch> = vec![];
+
+    for found in matches {
+        let package = &found.package;
+        let qualified_name = package.name.as_str();
+        // Attribute paths from channels are prepended with the channel name,
+        // e.g. "nixos." or "nixpkgs.". Attribute paths from multiple flakes
+        // are prepended with the flake reference, e.g. "nixpkgs#".
+        let (flake, attr_path, name) = match flakes.as_slice() {
+            // Merged matches have plain names
+            _ if cli.merge => (None, qualified_name, qualified_name),
+            [] => (
+                None,
+                qualified_name,
+                qualified_name
+                    .split_once('.')
+                    .map(|(_channel, name)| name)
+                    .unwrap_or(qualified_name),
+            ),
+            [flake] => (Some(*flake), qualified_name, qualified_name),
+            _ => {
+                let (flake, attr_path) = qualified_name
+                    .split_once('#')
+                    .ok_or("Can't get flake from package name")?;
+                (Some(flake), attr_path, attr_path)
+            }
+        };
+
+        json_matches.push(JsonMatch {
+            name,
+            attr_path,
+            flake,
+            sources: found
+                .sources
+                .as_ref()
+                .map(|sources| sources.iter().map(String::as_str).collect()),
+            version: &package.version,
+            description: &package.description,
+            match_type: package.kind,
+        });
+    }
+
+    // Let's have the top results at the bottom by default
+    if !cli.flip {
+        json_matches.reverse();
+    }
+
+    let mut stdout = io::stdout();
+    match cli.output {
+        OutputChoice::Ndjson => {
+            for json_match in json_matches {
+                serde_json::to_writer(&mut stdout, &json_match)
+                    .map_err(|err| format!("Can't serialize match: {err}"))?;
+                writeln!(stdout).map_err(|err| format!("Can't write to stdout: {err}"))?;
+            }
+        }
+        _ => {
+            serde_json::to_writer(&mut stdout, &json_matches)
+                .map_err(|err| format!("Can't serialize matches: {err}"))?;
+            writeln!(stdout).map_err(|err| format!("Can't write to stdout: {err}"))?;
+        }
+    }
+
+    Ok(())
+}
 
-    Ok(find_flakes.success())
+/// Cache folder that refreshes write to, the system one with `--system`
+fn own_cache_folder(cli: &Cli) -> &Path {
+    match cli.system {
+        true => &cli.system_cache_folder,
+        false => &cli.cache_folder,
+    }
+}
+
+/// Cache folder to read the cache of channels (`None`) or a flake from
+fn cache_folder<'a>(cli: &'a Cli, context: &'a SearchContext, source: Option<&str>) -> &'a Path {
+    match context.cache_folders.get(&source.map(str::to_string)) {
+        Some(cache_folder) => cache_folder,
+        None => own_cache_folder(cli),
+    }
 }
 
-/// Check if requested `nps` features match system features
+/// Pick the fresher one of the own and the system cache of every source
 ///
-/// Give helpful warnings if there is a mismatch.
-fn check_for_features(
-    flakes_enabled: bool,
-    experimental: bool,
+/// An unreadable system cache is skipped with a message, the own cache is
+/// used instead.
+fn select_cache_folders(
+    cli: &Cli,
+    sources: &[Option<&str>],
+) -> Result<HashMap<Option<String>, PathBuf>, Box<dyn Error>> {
+    let mut cache_folders = HashMap::new();
+    if cli.system || cli.system_cache_folder == cli.cache_folder {
+        return Ok(cache_folders);
+    }
+
+    for source in sources {
+        let source_name = source.unwrap_or("channels");
+        let system_created = match Cache::created(&cli.system_cache_folder, *source) {
+            Ok(created) => created,
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                let denied_message = format!(
+                    "Can't read system cache of {source_name} in {}, permission denied. Using \
+                    the own cache instead, ask an administrator to make it readable, e.g. with \
+                    `chmod -R a+rX {}`.",
+                    cli.system_cache_folder.display(),
+                    cli.system_cache_folder.display()
+                );
+                log::warn!("{}", denied_message);
+                notice(&denied_message, cli.quiet)?;
+                None
+            }
+            Err(err) => {
+                log::warn!(
+                    "Can't read system cache of {source_name} in {}: {err}",
+                    cli.system_cache_folder.display()
+                );
+                None
+            }
+        };
+        let own_created = Cache::created(&cli.cache_folder, *source).map_err(|err| {
+            let hint = match err.kind() {
+                io::ErrorKind::PermissionDenied => ", check its permissions",
+                _ => "",
+            };
+            format!(
+                "Can't read cache of {source_name} in {}{hint}: {err}",
+                cli.cache_folder.display()
+            )
+        })?;
+        log::debug!("system cache created: {system_created:?}, own cache created: {own_created:?}");
+
+        let use_system = match (system_created, own_created) {
+            (Some(system_created), Some(own_created)) => system_created > own_created,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if use_system {
+            log::debug!(
+                "using system cache of {source_name} in {}",
+                cli.system_cache_folder.display()
+            );
+            cache_folders.insert(source.map(str::to_string), cli.system_cache_folder.clone());
+        }
+    }
+
+    Ok(cache_folders)
+}
+
+/// Fail early if the cache folder can't be written, refreshing takes a while
+fn check_writable(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let folder = own_cache_folder(cli);
+    let result =
+        fs::create_dir_all(folder).and_then(|()| tempfile::tempfile_in(folder).map(|_file| ()));
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            let hint = match cli.system {
+                true => "refresh the system cache as root, e.g. with `sudo nps --refresh --system`",
+                false => "check its permissions",
+            };
+            Err(format!(
+                "Can't write to cache folder {}, permission denied. Please {hint}.",
+                folder.display()
+            )
+            .into())
+        }
+        Err(err) => Err(format!("Can't write to cache folder {}: {err}", folder.display()).into()),
+    }
+}
+
+/// Live progress line of a refresh, drawn on stderr if it's a terminal
+struct ProgressLine {
     quiet: bool,
-) -> Result<(), Box<dyn Error>> {
-    if flakes_enabled && !experimental {
-        let flakes_messages = [
-            "Feature mismatch:",
-            "> Your system seems to be based on flakes.",
-            "> You may want to use `nps -e=true ...` instead to enable querying flake-based packages.",
-        ];
-        for flake_message in flakes_messages {
-            message(flake_message, quiet)?;
-            log::warn!("{}", flake_message);
+    enabled: bool,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressLine {
+    fn new(quiet: bool) -> Self {
+        ProgressLine {
+            quiet,
+            enabled: !quiet && io::stderr().is_terminal(),
+            last_draw: None,
         }
     }
-    if !flakes_enabled && experimental {
-        let channels_messages = [
-            "Feature mismatch:",
-            "> Your system seems to be based on channels.",
-            "> You may want to use `nps -e=false ...` instead to query packages from channels.",
-        ];
-        for channel_message in channels_messages {
-            message(channel_message, quiet)?;
-            log::warn!("{}", channel_message);
+
+    /// Show the progress of a refresh, redrawing at most once per `PROGRESS_INTERVAL`
+    ///
+    /// Progress is cosmetic, failures to write it are ignored.
+    fn report(&mut self, progress: RefreshProgress) {
+        match progress {
+            RefreshProgress::Message(message_string) => {
+                self.clear();
+                let _ = notice(message_string, self.quiet);
+            }
+            RefreshProgress::Running { elapsed, attribute } => {
+                if !self.enabled
+                    || self
+                        .last_draw
+                        .is_some_and(|last_draw| last_draw.elapsed() < PROGRESS_INTERVAL)
+                {
+                    return;
+                }
+                self.last_draw = Some(Instant::now());
+                let mut stderr = io::stderr();
+                let _ = write!(stderr, "\r\x1b[2K{}", progress_line(elapsed, attribute));
+                let _ = stderr.flush();
+            }
+            RefreshProgress::Stopped => self.clear(),
+        }
+    }
+
+    /// Remove the progress line, if one was drawn
+    fn clear(&mut self) {
+        if self.last_draw.take().is_some() {
+            let _ = write!(io::stderr(), "\r\x1b[2K");
         }
     }
+}
+
+/// Progress line with the elapsed time and the attribute being evaluated
+fn progress_line(elapsed: Duration, attribute: &str) -> String {
+    let seconds = elapsed.as_secs();
+    let mut line = format!("Refreshing cache {}:{:02}", seconds / 60, seconds % 60);
+    if !attribute.is_empty() {
+        line.push_str(&format!(", evaluating {attribute}"));
+    }
+    if line.chars().count() > PROGRESS_WIDTH {
+        line = line.chars().take(PROGRESS_WIDTH - 3).collect();
+        line.push_str("...");
+    }
+    line
+}
+
+/// Refresh the cache of channels (`None`) or a flake in `cache_folder`
+///
+/// Ctrl-C cancels the refresh instead of exiting, see `main`.
+fn refresh_cache(
+    cli: &Cli,
+    cache_folder: &Path,
+    flake: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut progress_line = ProgressLine::new(cli.quiet);
+    CANCEL_REFRESH.store(false, Ordering::SeqCst);
+    REFRESHING.store(true, Ordering::SeqCst);
+    let refreshed = nps::refresh(
+        cache_folder,
+        flake,
+        &mut |progress| progress_line.report(progress),
+        &CANCEL_REFRESH,
+    );
+    REFRESHING.store(false, Ordering::SeqCst);
+    progress_line.clear();
+    refreshed
+}
+
+/// Load the cache of channels (`None`) or a flake for searching
+///
+/// Create it first, if missing or of another format. Deal with stale caches,
+/// if requested.
+fn prepare_cache(
+    cli: &Cli,
+    context: &SearchContext,
+    flake: Option<&str>,
+) -> Result<Cache, Box<dyn Error>> {
+    let cache_folder = cache_folder(cli, context, flake);
+    log::trace!("cache_folder: {:?}", cache_folder);
+
+    let cache_created =
+        Cache::created(cache_folder, flake).map_err(|err| format!("Can't read cache: {err}"))?;
+    log::trace!("cache_created: {:?}", cache_created);
+
+    if cache_created.is_none() {
+        refresh_cache(cli, cache_folder, flake)
+            .map_err(|err| format!("Can't refresh cache: {err}"))?;
+    }
+
+    let cache =
+        Cache::load(cache_folder, flake).map_err(|err| format!("Can't load cache: {err}"))?;
+    log::debug!("cache header: {:?}", cache.header);
+
+    // Deal with stale caches, if requested
+    let Some(stale_age) = stale_cache_age(&cache.header, cli.max_cache_age)
+        .map_err(|err| format!("Can't check cache age: {err}"))?
+    else {
+        return Ok(cache);
+    };
+
+    let stale_message = format!(
+        "Cache of {} is {} old.",
+        flake.unwrap_or("channels"),
+        format_age(stale_age)
+    );
+    log::warn!("{}", stale_message);
+    match cli.stale_cache {
+        StaleCacheChoice::Warn => {
+            notice(
+                &format!("{stale_message} Refresh with `nps -r`."),
+                cli.quiet,
+            )?;
+            Ok(cache)
+        }
+        StaleCacheChoice::Refresh => {
+            // The system cache might be read-only, refresh the own one instead
+            let cache_folder = own_cache_folder(cli);
+            refresh_cache(cli, cache_folder, flake)
+                .map_err(|err| format!("Can't refresh stale cache: {err}"))?;
+            Cache::load(cache_folder, flake)
+                .map_err(|err| format!("Can't load cache: {err}").into())
+        }
+        StaleCacheChoice::Background => {
+            spawn_background_refresh(cli, flake)
+                .map_err(|err| format!("Can't refresh stale cache: {err}"))?;
+            notice(
+                &format!("{stale_message} Refreshing it in the background."),
+                cli.quiet,
+            )?;
+            Ok(cache)
+        }
+    }
+}
+
+/// Settings of the config file, top-level ones and named profiles
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: HashMap<String, toml::Table>,
+    #[serde(flatten)]
+    settings: toml::Table,
+}
+
+/// Where the value of a setting comes from
+#[derive(Clone, Debug, PartialEq)]
+enum SettingSource {
+    CommandLine,
+    Environment(String),
+    ConfigFile(PathBuf),
+    Profile(String),
+    Default,
+}
+
+impl fmt::Display for SettingSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingSource::CommandLine => write!(f, "command line"),
+            SettingSource::Environment(var) => write!(f, "environment variable {var}"),
+            SettingSource::ConfigFile(path) => write!(f, "config file {}", path.display()),
+            SettingSource::Profile(name) => write!(f, "profile {name}"),
+            SettingSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// Effective value of a setting, `None` if it isn't set
+#[derive(Clone, Debug)]
+struct Setting {
+    key: String,
+    value: Option<toml::Value>,
+    source: SettingSource,
+}
+
+/// XDG base directory of environment variable `var`, or `fallback` in the home dir
+fn xdg_base_dir(var: &str, fallback: &str) -> PathBuf {
+    // Relative paths are invalid according to the XDG spec
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .unwrap_or_else(|| {
+            home::home_dir()
+                .unwrap() // We previously made sure this works.
+                .join(fallback)
+        })
+}
+
+/// Path of the config file, `$XDG_CONFIG_HOME/nps/config.toml`
+fn config_file_path() -> PathBuf {
+    xdg_base_dir("XDG_CONFIG_HOME", ".config")
+        .join("nps")
+        .join("config.toml")
+}
+
+/// Default cache folder, `$XDG_CACHE_HOME/nps`
+fn default_cache_folder() -> PathBuf {
+    xdg_base_dir("XDG_CACHE_HOME", ".cache").join(DEFAULTS.cache_folder)
+}
+
+/// Move the caches of `legacy_folder` to `cache_folder`
+///
+/// Caches that already exist in `cache_folder` are kept, their legacy
+/// versions are removed with `legacy_folder`. Returns whether there was
+/// anything to move.
+fn migrate_cache_folder(legacy_folder: &Path, cache_folder: &Path) -> Result<bool, Box<dyn Error>> {
+    if !legacy_folder.is_dir() {
+        return Ok(false);
+    }
+    log::debug!(
+        "moving legacy cache folder {} to {}",
+        legacy_folder.display(),
+        cache_folder.display()
+    );
+
+    if !cache_folder.exists() {
+        if let Some(parent) = cache_folder.parent() {
+            fs::create_dir_all(parent).map_err(|err| format!("Can't create folder: {err}"))?;
+        }
+        match fs::rename(legacy_folder, cache_folder) {
+            Ok(()) => return Ok(true),
+            // Most likely on another file system, move file by file instead
+            Err(err) => log::debug!("can't rename cache folder: {err}"),
+        }
+    }
+
+    fs::create_dir_all(cache_folder).map_err(|err| format!("Can't create folder: {err}"))?;
+    let entries =
+        fs::read_dir(legacy_folder).map_err(|err| format!("Can't read legacy folder: {err}"))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("Can't read legacy folder: {err}"))?;
+        let target = cache_folder.join(entry.file_name());
+        if target.exists() {
+            log::debug!("keeping {}", target.display());
+            continue;
+        }
+        fs::rename(entry.path(), &target)
+            .or_else(|_err| fs::copy(entry.path(), &target).map(|_bytes| ()))
+            .map_err(|err| format!("Can't move {}: {err}", entry.path().display()))?;
+    }
+    fs::remove_dir_all(legacy_folder)
+        .map_err(|err| format!("Can't remove legacy folder: {err}"))?;
+
+    Ok(true)
+}
+
+/// Read the config file, a missing one has no settings
+fn load_config(path: &Path) -> Result<ConfigFile, Box<dyn Error>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log::debug!("no config file at {}", path.display());
+            return Ok(ConfigFile::default());
+        }
+        Err(err) => return Err(format!("Can't read config file {}: {err}", path.display()).into()),
+    };
+    toml::from_str(&content)
+        .map_err(|err| format!("Can't parse config file {}: {err}", path.display()).into())
+}
+
+/// Config file key of an option, if it can be set by an environment variable
+fn config_key(arg: &clap::Arg) -> Option<String> {
+    arg.get_env()?
+        .to_str()?
+        .strip_prefix(ENV_PREFIX)
+        .map(str::to_lowercase)
+}
+
+/// Command line arguments setting option `--long` to a config file value
+fn config_args(long: &str, key: &str, value: &toml::Value) -> Result<Vec<OsString>, String> {
+    let values = match value {
+        toml::Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    values
+        .into_iter()
+        .map(|value| {
+            let value = match value {
+                toml::Value::String(value) => value.clone(),
+                toml::Value::Boolean(value) => value.to_string(),
+                toml::Value::Integer(value) => value.to_string(),
+                value => {
+                    return Err(format!(
+                        "Setting `{key}` can't be a {}, use a string instead",
+                        value.type_str()
+                    ))
+                }
+            };
+            Ok(format!("--{long}={value}").into())
+        })
+        .collect()
+}
+
+/// Config file value of the raw values of an option
+fn setting_value(arg: &clap::Arg, raw: Vec<&str>) -> toml::Value {
+    if let ArgAction::Append = arg.get_action() {
+        return toml::Value::Array(raw.into_iter().map(toml::Value::from).collect());
+    }
+    match raw.concat().as_str() {
+        "true" => toml::Value::Boolean(true),
+        "false" => toml::Value::Boolean(false),
+        value => toml::Value::from(value),
+    }
+}
+
+/// Parse command line arguments, filling in settings from the config file
+///
+/// Settings come from the command line, environment variables, the profile
+/// picked with `--profile`, the top level of the config file and `DEFAULTS`,
+/// in this order.
+/// Also returns the effective settings and their sources, for `--print-config`.
+fn parse_cli(
+    args: Vec<OsString>,
+    config_path: &Path,
+) -> Result<(Cli, Vec<Setting>), Box<dyn Error>> {
+    let command = Cli::command();
+    let matches = command.clone().try_get_matches_from(&args)?;
+
+    let config = load_config(config_path)?;
+    let keys: Vec<(String, &clap::Arg)> = command
+        .get_arguments()
+        .filter_map(|arg| Some((config_key(arg)?, arg)))
+        .collect();
+
+    // Catch typos, also in profiles that aren't used
+    let tables = std::iter::once((None, &config.settings)).chain(
+        config
+            .profile
+            .iter()
+            .map(|(name, table)| (Some(name), table)),
+    );
+    for (profile, table) in tables {
+        if let Some(key) = table
+            .keys()
+            .find(|key| !keys.iter().any(|(known, _arg)| known == *key))
+        {
+            let place = match profile {
+                Some(name) => format!("profile `{name}` of config file"),
+                None => "config file".to_string(),
+            };
+            let mut known: Vec<&str> = keys.iter().map(|(known, _arg)| known.as_str()).collect();
+            known.sort();
+            return Err(format!(
+                "Unknown setting `{key}` in {place} {}, settings are {}",
+                config_path.display(),
+                known.join(", ")
+            )
+            .into());
+        }
+    }
+
+    let profile = match matches.get_one::<String>("profile") {
+        Some(name) => Some((
+            name,
+            config.profile.get(name).ok_or_else(|| {
+                format!(
+                    "Can't find profile `{name}` in config file {}",
+                    config_path.display()
+                )
+            })?,
+        )),
+        None => None,
+    };
+
+    // Hand settings of the config file to clap as command line arguments, for
+    // options that aren't set yet
+    let mut injected = vec![];
+    let mut sources = HashMap::new();
+    for (key, arg) in &keys {
+        let id = arg.get_id().as_str();
+        if let Some(ValueSource::CommandLine | ValueSource::EnvVariable) = matches.value_source(id)
+        {
+            continue;
+        }
+        let profile_value = profile.and_then(|(name, table)| Some((name, table.get(key)?)));
+        let (value, source) = match (profile_value, config.settings.get(key)) {
+            (Some((name, value)), _) => (value, SettingSource::Profile(name.clone())),
+            (None, Some(value)) => (value, SettingSource::ConfigFile(config_path.to_path_buf())),
+            (None, None) => continue,
+        };
+        let long = arg.get_long().expect("settings have long options");
+        injected.extend(config_args(long, key, value)?);
+        sources.insert(id, source);
+    }
+    log::trace!("config args: {:?}", injected);
+
+    let (executable, rest) = args.split_at(args.len().min(1));
+    let final_matches = command
+        .clone()
+        .try_get_matches_from(executable.iter().chain(&injected).chain(rest))
+        .map_err(|err| {
+            let err = err.to_string();
+            let err = err.lines().next().unwrap_or_default();
+            format!(
+                "Invalid setting in config file {}: {}",
+                config_path.display(),
+                err.trim_start_matches("error: ")
+            )
+        })?;
+    let cli = Cli::from_arg_matches(&final_matches)?;
+
+    let mut settings: Vec<Setting> = keys
+        .iter()
+        .map(|(key, arg)| {
+            let id = arg.get_id().as_str();
+            let source = match matches.value_source(id) {
+                Some(ValueSource::CommandLine) => SettingSource::CommandLine,
+                Some(ValueSource::EnvVariable) => SettingSource::Environment(
+                    arg.get_env()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                _ => sources.remove(id).unwrap_or(SettingSource::Default),
+            };
+            let value = final_matches
+                .get_raw(id)
+                .map(|raw| setting_value(arg, raw.filter_map(|value| value.to_str()).collect()));
+            Setting {
+                key: key.clone(),
+                value,
+                source,
+            }
+        })
+        .collect();
+    settings.sort_by(|a, b| a.key.cmp(&b.key));
+
+    Ok((cli, settings))
+}
+
+/// Print the effective settings in config file format, sources as comments
+fn print_config(settings: &[Setting]) -> Result<(), Box<dyn Error>> {
+    let lines: Vec<(String, Option<&SettingSource>)> = settings
+        .iter()
+        .map(|setting| match &setting.value {
+            Some(value) => (format!("{} = {value}", setting.key), Some(&setting.source)),
+            None => (format!("# {} is not set", setting.key), None),
+        })
+        .collect();
+    let width = lines
+        .iter()
+        .filter(|(_line, source)| source.is_some())
+        .map(|(line, _source)| line.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for (line, source) in lines {
+        match source {
+            Some(source) => out.push_str(&format!("{line:width$}  # {source}\n")),
+            None => out.push_str(&format!("{line}\n")),
+        }
+    }
+    io::stdout()
+        .write_all(out.as_bytes())
+        .map_err(|err| format!("Can't write to stdout: {err}").into())
+}
+
+fn main() -> ExitCode {
+    // Get home dir errors out of the way, since clap can't propagate errors
+    // from `derive`.
+    let home = home::home_dir();
+    if home.is_none() || home == Some("".into()) {
+        Builder::new().filter_level(LevelFilter::Trace).init();
+        log::error!("Can't find home dir.");
+        return ExitCode::FAILURE;
+    }
+    let (cli, settings) = match parse_cli(std::env::args_os().collect(), &config_file_path()) {
+        Ok(parsed) => parsed,
+        Err(err) => match err.downcast::<clap::Error>() {
+            Ok(err) => err.exit(),
+            Err(err) => {
+                Builder::new().filter_level(LevelFilter::Trace).init();
+                log::error!("{err}");
+                return ExitCode::FAILURE;
+            }
+        },
+    };
+
+    let log_level = match cli.debug {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    Builder::new().filter_level(log_level).init();
+
+    if cli.debug > 4 {
+        log::error!("Max log level is 4, e.g. -dddd");
+        return ExitCode::FAILURE;
+    }
+
+    log::debug!("Log level set to: {}", log_level);
+
+    // Ctrl-C cancels refreshes cleanly, anything else just exits
+    if let Err(err) = ctrlc::set_handler(|| match REFRESHING.load(Ordering::SeqCst) {
+        true => CANCEL_REFRESH.store(true, Ordering::SeqCst),
+        false => std::process::exit(130),
+    }) {
+        log::warn!("Can't handle Ctrl-C: {err}");
+    }
+
+    if cli.print_config {
+        return match print_config(&settings) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                log::error!("Can't print config: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    // Caches used to clutter the home dir, move them to the XDG cache dir
+    if cli.cache_folder == default_cache_folder() {
+        let legacy_folder = home::home_dir()
+            .unwrap() // We previously made sure this works.
+            .join(DEFAULTS.legacy_cache_folder);
+        match migrate_cache_folder(&legacy_folder, &cli.cache_folder) {
+            Ok(true) => {
+                let moved_message = format!(
+                    "Moved caches from {} to {}.",
+                    legacy_folder.display(),
+                    cli.cache_folder.display()
+                );
+                log::info!("{}", moved_message);
+                if let Err(err) = notice(&moved_message, cli.quiet) {
+                    log::error!("{err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+            Ok(false) => {}
+            Err(err) => log::warn!("Can't move legacy cache folder: {err}"),
+        }
+    }
+
+    // Read the system cache instead of the own one, if it's fresher
+    let mut context = SearchContext::default();
+    if !cli.refresh {
+        context.cache_folders = match select_cache_folders(&cli, &sources(&cli)) {
+            Ok(cache_folders) => cache_folders,
+            Err(err) => {
+                log::error!("{err}");
+                return ExitCode::FAILURE;
+            }
+        };
+    }
+
+    // Set a supports-color override based on the variable passed in.
+    let color_choice = match cli.color {
+        clap::ColorChoice::Always => {
+            log::debug!("clap::ColorChoice set to Always");
+            termcolor::ColorChoice::Always
+        }
+        clap::ColorChoice::Auto => {
+            log::debug!("clap::ColorChoice request Auto");
+            if io::stdout().is_terminal() {
+                log::debug!("Running in terminal, clap::ColorChoice set to Auto");
+                termcolor::ColorChoice::Auto
+            } else {
+                log::warn!("Not running in terminal, clap::ColorCoice forced to Never");
+                termcolor::ColorChoice::Never
+            }
+        }
+        clap::ColorChoice::Never => {
+            log::debug!("clap::ColorChoice set to Never");
+            termcolor::ColorChoice::Never
+        }
+    };
+
+    if !cli.fixed_strings {
+        for term in cli.search_term.iter().filter(|term| term.literal) {
+            let literal_message = format!(
+                "\"{}\" doesn't look like a regex, searching for it literally.",
+                term.pattern
+            );
+            log::warn!("{}", literal_message);
+            if let Err(err) = notice(&literal_message, cli.quiet) {
+                log::error!("{err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let sources = sources(&cli);
+    log::trace!("sources: {:?}", sources);
+    log::trace!("cli.refresh: {}", cli.refresh);
+
+    // Refresh cache with new info and exit?
+    if cli.refresh {
+        if let Err(err) = check_writable(&cli) {
+            log::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+        for source in sources.iter() {
+            if let Err(err) = refresh_cache(&cli, own_cache_folder(&cli), *source) {
+                log::error!("Can't refresh cache: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+        if !cli.changes {
+            return ExitCode::SUCCESS;
+        }
+    }
+
+    if cli.changes {
+        return match show_changes(&cli, &context, &sources) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                log::error!("Can't list changes: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut contents = vec![];
+    for source in sources.iter().copied() {
+        match prepare_cache(&cli, &context, source) {
+            Ok(cache) => contents.push((source, cache)),
+            Err(err) => {
+                log::error!("{err}");
+                return ExitCode::FAILURE;
+            }
+        };
+    }
+
+    // Metadata is written by refreshes, so only load it once the caches are ready
+    if cli.bin.is_some() || has_filters(&cli) {
+        let meta = match display_meta(&cli, &contents) {
+            Ok(meta) => meta,
+            Err(err) => {
+                log::error!("Can't load package metadata: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        if has_filters(&cli) {
+            context.allowed_packages = Some(allowed_packages(&cli, &meta));
+        }
+        if cli.bin.is_some() {
+            context.bin_matches = match bin_matches(&cli, &meta) {
+                Ok(bin_matches) => bin_matches,
+                Err(err) => {
+                    log::error!("Can't find packages providing executable: {err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+        }
+    }
+
+    if cli.info.is_some() {
+        return match show_info(&cli, &contents) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                log::error!("Can't show package info: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if let Some(action) = action(&cli) {
+        return match run_action(&cli, action, &contents) {
+            Ok(exit_code) => exit_code,
+            Err(err) => {
+                log::error!("Can't {:?} packages: {err}", action);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if cli.interactive {
+        let color = cli.color != clap::ColorChoice::Never;
+        return match interactive::run(&cli, &context, &contents, color) {
+            Ok(Some(name)) => match writeln!(io::stdout(), "{name}") {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => {
+                    log::error!("Can't write to stdout: {err}");
+                    ExitCode::FAILURE
+                }
+            },
+            // Nothing picked
+            Ok(None) => ExitCode::FAILURE,
+            Err(err) => {
+                log::error!("Can't run interactive mode: {err}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let matches = match search(&cli, &context, &cli.search_term, &contents) {
+        Ok(matches) => matches,
+        Err(err) => {
+            log::error!("Can't get matches: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Still fail on no matches, but help out with similar package names
+    if matches.is_empty() {
+        if cli.search_term.is_empty() {
+            return ExitCode::FAILURE;
+        }
+        match suggestions(&cli, &contents) {
+            Ok(names) if !names.is_empty() => {
+                let mut out = format!(
+                    "No matches for \"{}\", did you mean",
+                    cli.search_term
+                        .iter()
+                        .map(QueryTerm::to_string)
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                );
+                for name in names {
+                    out.push_str(&format!("\n  {name}"));
+                }
+                if let Err(err) = notice(&out, cli.quiet) {
+                    log::error!("{err}");
+                }
+            }
+            Ok(_) => {}
+            Err(err) => log::error!("Can't get suggestions: {err}"),
+        }
+        return ExitCode::FAILURE;
+    }
+
+    // Structured output skips padding and coloring
+    if let OutputChoice::Json | OutputChoice::Ndjson = cli.output {
+        if let Err(err) = print_json_matches(&cli, &matches) {
+            log::error!("Can't print matches: {err}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if let Err(err) = print_matches(&cli, &matches, color_choice) {
+        log::error!("Can't print matches: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nps::{CacheSource, ChangedPackage, Highlights};
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    /// Write a cache of channels (`None`) or a flake with the package lines
+    /// `body` to `folder`
+    ///
+    /// Flakes are written to the cache file of the default flake.
+    fn write_cache(folder: &Path, flake: Option<&str>, created: u64, body: &str) {
+        let header = CacheHeader {
+            format: nps::CACHE_FORMAT,
+            source: match flake {
+                Some(_) => CacheSource::Flake,
+                None => CacheSource::Channels,
+            },
+            flake: flake.map(str::to_string),
+            revision: None,
+            created,
+            nps_version: "0.2.5".to_string(),
+        };
+        let file_name = match flake {
+            Some(_) => DEFAULTS.experimental_cache_file,
+            None => DEFAULTS.cache_file,
+        };
+        fs::write(
+            folder.join(file_name),
+            format!("{}\n{body}", serde_json::to_string(&header).unwrap()),
+        )
+        .unwrap();
+    }
+
+    /// Cache of channels (`None`) or a flake with the package lines `body`
+    pub(crate) fn cache(flake: Option<&str>, body: &str) -> Cache {
+        let folder = tempfile::TempDir::new().unwrap();
+        write_cache(folder.path(), flake, 0, body);
+        Cache::load(folder.path(), flake.map(|_flake| DEFAULTS.flake)).unwrap()
+    }
+
+    /// Names of the packages in `cache` matching the search terms, best first
+    fn match_names(cli: &Cli, source: Option<&str>, cache: &Cache) -> Vec<String> {
+        find_matches(cli, &cli.search_term, source, cache)
+            .unwrap()
+            .into_iter()
+            .map(|found| found.name)
+            .collect()
+    }
+
+    #[test]
+    fn test_progress_line() {
+        assert_eq!(
+            progress_line(Duration::from_secs(65), ""),
+            "Refreshing cache 1:05"
+        );
+        assert_eq!(
+            progress_line(Duration::from_secs(3), "legacyPackages.x86_64-linux.hello"),
+            "Refreshing cache 0:03, evaluating legacyPackages.x86_64-linux.hello"
+        );
+        let line = progress_line(
+            Duration::from_secs(3),
+            "legacyPackages.x86_64-linux.python3Packages.a-package-with-a-very-long-name",
+        );
+        assert_eq!(line.chars().count(), PROGRESS_WIDTH);
+        assert!(line.ends_with("..."));
+    }
+
+    #[test]
+    fn test_find_matches() {
+        init();
+
+        let cli = Cli::try_parse_from(vec!["nps", "second"]).unwrap();
+        let cache = cache(
+            None,
+            "\
+            the first line\n\
+            the second line\n\
+            the third line\
+            ",
+        );
+
+        assert_eq!(match_names(&cli, None, &cache), vec!["the second line"]);
+
+        let cli_all = Cli::try_parse_from(vec!["nps", "line", "ir"]).unwrap();
+        let cli_any = Cli::try_parse_from(vec!["nps", "--any", "first", "third"]).unwrap();
+
+        assert_eq!(
+            match_names(&cli_all, None, &cache),
+            vec!["the first line", "the third line"]
+        );
+        assert_eq!(
+            match_names(&cli_any, None, &cache),
+            vec!["the first line", "the third line"]
+        );
+    }
+
+    #[test]
+    fn test_find_matches_per_field() {
+        init();
+
+        let cache = cache(
+            None,
+            "\
+            rustup\t1.27.1\tThe Rust toolchain installer\n\
+            rust-analyzer\t2024-12-09\tA modular compiler frontend for the Rust language\n\
+            vscode-extensions.rust-lang.rust-analyzer\t0.3.2220\tRust analyzer for VS Code\n\
+            ra-multiplex\t0.2.5\tShare one rust-analyzer server\
+            ",
+        );
+
+        let cli = Cli::try_parse_from(vec!["nps", "name:rust", "desc:analyzer"]).unwrap();
+        assert_eq!(
+            match_names(&cli, None, &cache),
+            vec!["vscode-extensions.rust-lang.rust-analyzer"]
+        );
+
+        let cli = Cli::try_parse_from(vec!["nps", "version:^1\\."]).unwrap();
+        assert_eq!(match_names(&cli, None, &cache), vec!["rustup"]);
+
+        let cli = Cli::try_parse_from(vec!["nps", "--any", "name:^ra-", "version:^1\\."]).unwrap();
+        assert_eq!(
+            match_names(&cli, None, &cache),
+            vec!["rustup", "ra-multiplex"]
+        );
+    }
+
+    #[test]
+    fn test_action_command() {
+        init();
+
+        let channel_packages = vec![
+            (None, "nixos.ripgrep".to_string()),
+            (None, "nixos.fd".to_string()),
+        ];
+        let flake_packages = vec![
+            (Some("nixpkgs"), "ripgrep".to_string()),
+            (Some("github:me/my-flake"), "fd".to_string()),
+        ];
+
+        assert_eq!(
+            action_command(Action::Run, &channel_packages[..1]).unwrap(),
+            vec!["nix-shell", "-p", "ripgrep", "--run", "ripgrep"]
+        );
+        assert_eq!(
+            action_command(Action::Run, &flake_packages[..1]).unwrap(),
+            vec!["nix", "run", "nixpkgs#ripgrep"]
+        );
+        assert!(action_command(Action::Run, &flake_packages).is_err());
+        assert_eq!(
+            action_command(Action::Shell, &channel_packages).unwrap(),
+            vec!["nix-shell", "-p", "ripgrep", "fd"]
+        );
+        assert_eq!(
+            action_command(Action::Shell, &flake_packages).unwrap(),
+            vec!["nix", "shell", "nixpkgs#ripgrep", "github:me/my-flake#fd"]
+        );
+        assert_eq!(
+            action_command(Action::Install, &channel_packages).unwrap(),
+            vec!["nix-env", "-iA", "nixos.ripgrep", "nixos.fd"]
+        );
+        assert_eq!(
+            action_command(Action::Install, &flake_packages).unwrap(),
+            vec![
+                "nix",
+                "profile",
+                "install",
+                "nixpkgs#ripgrep",
+                "github:me/my-flake#fd"
+            ]
+        );
+        assert!(action_command(
+            Action::Install,
+            &[channel_packages[0].clone(), flake_packages[0].clone()]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_exact_packages() {
+        init();
+
+        let contents = vec![(
+            None,
+            cache(
+                None,
+                "\
+                nixpkgs.ripgrep-all\t0.10.6\tRipgrep, but also search in PDFs\n\
+                nixpkgs.ripgrep\t14.1.1\tA search tool\n\
+                nixos.ripgrep\t14.1.1\tA search tool\n\
+                nixpkgs.fd\t10.2.0\tA simple, fast alternative to find\
+                ",
+            ),
+        )];
+
+        let cli = Cli::try_parse_from(vec!["nps", "--shell", "ripgrep", "FD"]).unwrap();
+        assert_eq!(
+            exact_packages(&cli, &contents).unwrap(),
+            vec![
+                (None, "nixpkgs.ripgrep".to_string()),
+                (None, "nixpkgs.fd".to_string())
+            ]
+        );
+
+        let cli = Cli::try_parse_from(vec!["nps", "--shell", "rip"]).unwrap();
+        assert!(exact_packages(&cli, &contents).is_err());
+    }
+
+    #[test]
+    fn test_find_matches_fixed_strings() {
+        init();
+
+        let cache = cache(
+            None,
+            "\
+            gtk3\t3.24.43\tA multi-platform toolkit\n\
+            gtkmm3\t3.24.9\tC++ interface for gtk+3\n\
+            clang\t19.1.6\tA C language family frontend for LLVM\
+            ",
+        );
+
+        let cli = Cli::try_parse_from(vec!["nps", "-F", "gtk+3"]).unwrap();
+        assert_eq!(match_names(&cli, None, &cache), vec!["gtkmm3"]);
+
+        // Looks like a name, searched for literally
+        let cli = Cli::try_parse_from(vec!["nps", "gtk+3"]).unwrap();
+        assert_eq!(match_names(&cli, None, &cache), vec!["gtkmm3"]);
+
+        // Other regex syntax makes it a regex
+        let cli = Cli::try_parse_from(vec!["nps", "^gtk+3"]).unwrap();
+        assert_eq!(match_names(&cli, None, &cache), vec!["gtk3"]);
+
+        // Not a valid regex, searched for literally
+        let cli = Cli::try_parse_from(vec!["nps", "c++"]).unwrap();
+        assert_eq!(match_names(&cli, None, &cache), vec!["gtkmm3"]);
+    }
+
+    #[test]
+    fn test_find_fuzzy_matches() {
+        init();
 
-    Ok(())
-}
+        let cli = Cli::try_parse_from(vec!["nps", "-e=true", "--fuzzy", "pyhton3"]).unwrap();
+        let cache = cache(
+            Some("nixpkgs"),
+            "\
+            python\t3.12\tA language\n\
+            python3Packages.numpy\t2.0\tNumbers\n\
+            python3\t3.12\tA language\n\
+            pythons3\t0.1\tSnakes\n\
+            jython\t2.7\tA language on the JVM\
+            ",
+        );
 
-/// Fetch new package info and write to cache file
-fn refresh(experimental: bool, file_path: &PathBuf, quiet: bool) -> Result<(), Box<dyn Error>> {
-    let flakes_enabled = check_flakes_enabled()?;
-    // Print helpful warnings if there is a feature mismatch
-    // between the system setup and the `nps` usage.
-    check_for_features(flakes_enabled, experimental, quiet)?;
-
-    let cache_start_message = "Refreshing cache. This might take a while...";
-    log::info!("{}", cache_start_message);
-    message(cache_start_message, quiet)?;
-
-    let cache_folder = file_path
-        .parent()
-        .ok_or("Can't get cache folder from file path")?;
-    log::trace!("file_path: {:?}", file_path);
-
-    let output = match experimental {
-        true => Command::new("nix")
-            .arg("--extra-experimental-features")
-            .arg("nix-command flakes")
-            .arg("search")
-            .arg("nixpkgs")
-            .arg("^")
-            .arg("--json")
-            .output()
-            .map_err(|err| format!("`nix search` failed: {err}"))?,
-        false => Command::new("nix-env")
-            .arg("-qaP")
-            .arg("--description")
-            .output()
-            .map_err(|err| format!("`nix-env` failed: {err}"))?,
-    };
+        assert_eq!(
+            match_names(&cli, Some("nixpkgs"), &cache),
+            vec!["python3", "python3Packages.numpy"]
+        );
+    }
 
-    log::trace!("finished cli command");
+    #[test]
+    fn test_suggestions() {
+        init();
 
-    let (stdout, stderr) = (
-        str::from_utf8(&output.stdout)
-            .map_err(|err| format!("Can't convert stdout to UTF8: {err}"))?,
-        str::from_utf8(&output.stderr)
-            .map_err(|err| format!("Can't convert stderr to UTF8: {err}"))?,
-    );
+        let cli = Cli::try_parse_from(vec!["nps", "python3Packages.reqests"]).unwrap();
+        let contents = vec![
+            (
+                None,
+                cache(
+                    None,
+                    "\
+                    nixpkgs.python2Packages.requests\t2.27\tHTTP for humans\n\
+                    nixpkgs.python3Packages.requests\t2.32\tHTTP for humans\n\
+                    nixpkgs.python3Packages.requests-oauthlib\t2.0\tOAuth for requests\n\
+                    nixpkgs.python3Packages.reqwest\t0.1\tNot the Rust crate\
+                    ",
+                ),
+            ),
+            (
+                None,
+                cache(
+                    None,
+                    "\
+                    nixos.python3Packages.requests\t2.32\tHTTP for humans\n\
+                    nixos.emacs\t29.4\tThe extensible editor\
+                    ",
+                ),
+            ),
+        ];
 
-    log::trace!("stdout.len(): {}", stdout.len());
-    log::trace!("stderr.len(): {}", stderr.len());
-
-    // Report warnings if stderr looks bad
-    let mut first_error = true;
-    for line in stderr.lines() {
-        // ignore standard logging to stderr
-        if !line.starts_with("evaluating") {
-            if first_error {
-                log::warn!("These warnings were encountered during cache refresh (START)");
-                first_error = false;
-            }
-            log::warn!("> {}", line);
-        }
-    }
-    if !first_error {
-        log::warn!("These warnings were encountered during cache refresh (END)");
-    }
+        assert_eq!(
+            suggestions(&cli, &contents).unwrap(),
+            vec![
+                "python3Packages.requests",
+                "python3Packages.reqwest",
+                "python2Packages.requests",
+            ]
+        );
 
-    // Throw error if cache is too small
-    if stdout.len() < 10_000 {
-        log::warn!("Cache seems too small:");
-        log::warn!("> Query returned only {} lines.", stdout.len());
-        if flakes_enabled {
-            log::info!(
-                "> Did you set up your channels yet? See: https://nixos.wiki/wiki/Nix_channels"
-            );
-            log::info!(
-                "> You can also set up your system for flakes instead. See: https://nixos.wiki/wiki/Flakes"
-            );
-        }
-        log::info!("> Run with `-dddd` flag for even more information.");
-        return Err("Cache seems too small. Run with `-dd` flag for more information.".into());
+        let cli = Cli::try_parse_from(vec!["nps", "zzz"]).unwrap();
+        assert!(suggestions(&cli, &contents).unwrap().is_empty());
     }
 
-    let cache_content = match experimental {
-        true => parse_json_to_lines(stdout).map_err(|err| format!("Can't parse JSON: {err}"))?,
-        false => {
-            // Replace in every line the first two series of whitespaces with single spaces
-            let re = regex::RegexBuilder::new(r"^([^ ]+) +([^ ]+) +(.*)$")
-                .multi_line(true)
-                .build()
-                .unwrap();
-            re.replace_all(stdout, "$1 $2 $3").to_string()
-        }
-    };
+    #[test]
+    fn test_edit_distances() {
+        init();
 
-    log::trace!("trying to create folder: {:?}", cache_folder);
-    // Create cache folder, if not exists
-    fs::create_dir_all(cache_folder).map_err(|err| format!("Can't create folder: {err}"))?;
-    log::trace!("folder created");
+        let distances = |term: &str, name: &str| {
+            let term: Vec<char> = term.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            edit_distances(&term, &name)
+        };
 
-    log::trace!("cache_folder: {:?}", cache_folder);
-    log::trace!("file_path: {:?}", &file_path);
-
-    // Atomic Writing: Write first to a tmp file, then persist (move) it to destination
-    let tempfile = NamedTempFile::new_in(cache_folder)
-        .map_err(|err| format!("Can't create temp file: {err}"))?;
-    log::trace!("tempfile: {:?}", &tempfile);
-    log::trace!("trying to write tempfile");
-    write!(&tempfile, "{}", cache_content)
-        .map_err(|err| format!("Can't write to temp file: {err}"))?;
-    log::trace!("tempfile written");
-
-    tempfile
-        .persist(file_path)
-        .map_err(|err| format!("Can't persist temp file: {err}"))?;
-    log::trace!("tempfile persisted");
-
-    let number_of_packages = cache_content.lines().count();
-    let cache_file_path_string = format!("{:?}", file_path);
-
-    let cache_end_message =
-        format!("Done. Cached info of {number_of_packages} packages in {cache_file_path_string}");
-    log::info!("{}", &cache_end_message);
-    message(&cache_end_message, quiet)?;
+        assert_eq!(distances("neovim", "neovim"), (0, 0));
+        assert_eq!(distances("neovmi", "neovim"), (1, 1));
+        assert_eq!(distances("pyhton3", "python3"), (1, 1));
+        assert_eq!(distances("neovim", "neovim-qt"), (3, 0));
+        assert_eq!(distances("", "vim"), (3, 0));
+        assert_eq!(distances("vim", ""), (3, 3));
+        assert!(is_subsequence(
+            &['n', 'v', 'm'],
+            &['n', 'e', 'o', 'v', 'i', 'm']
+        ));
+        assert!(!is_subsequence(
+            &['m', 'v'],
+            &['n', 'e', 'o', 'v', 'i', 'm']
+        ));
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_parse_cache_age() {
+        init();
 
-fn main() -> ExitCode {
-    // Get home dir errors out of the way, since clap can't propagate errors
-    // from `derive`.
-    let home = home::home_dir();
-    if home.is_none() || home == Some("".into()) {
-        Builder::new().filter_level(LevelFilter::Trace).init();
-        log::error!("Can't find home dir.");
-        return ExitCode::FAILURE;
+        assert_eq!(parse_cache_age("30s"), Ok(30));
+        assert_eq!(parse_cache_age("2m"), Ok(120));
+        assert_eq!(parse_cache_age("12h"), Ok(43_200));
+        assert_eq!(parse_cache_age("7d"), Ok(604_800));
+        assert_eq!(parse_cache_age("2w"), Ok(1_209_600));
+        assert!(parse_cache_age("7").is_err());
+        assert!(parse_cache_age("d").is_err());
+        assert!(parse_cache_age("7y").is_err());
     }
-    let cli = Cli::parse();
-
-    let log_level = match cli.debug {
-        0 => LevelFilter::Error,
-        1 => LevelFilter::Warn,
-        2 => LevelFilter::Info,
-        3 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
 
-    Builder::new().filter_level(log_level).init();
+    #[test]
+    fn test_format_age() {
+        init();
 
-    if cli.debug > 4 {
-        log::error!("Max log level is 4, e.g. -dddd");
-        return ExitCode::FAILURE;
+        assert_eq!(format_age(1), "1 second");
+        assert_eq!(format_age(119), "1 minute");
+        assert_eq!(format_age(7_200), "2 hours");
+        assert_eq!(format_age(700_000), "8 days");
     }
 
-    log::debug!("Log level set to: {}", log_level);
+    #[test]
+    fn test_match_type() {
+        init();
 
-    // Set a supports-color override based on the variable passed in.
-    let color_choice = match cli.color {
-        clap::ColorChoice::Always => {
-            log::debug!("clap::ColorChoice set to Always");
-            termcolor::ColorChoice::Always
-        }
-        clap::ColorChoice::Auto => {
-            log::debug!("clap::ColorChoice request Auto");
-            if io::stdout().is_terminal() {
-                log::debug!("Running in terminal, clap::ColorChoice set to Auto");
-                termcolor::ColorChoice::Auto
-            } else {
-                log::warn!("Not running in terminal, clap::ColorCoice forced to Never");
-                termcolor::ColorChoice::Never
-            }
-        }
-        clap::ColorChoice::Never => {
-            log::debug!("clap::ColorChoice set to Never");
-            termcolor::ColorChoice::Never
-        }
-    };
+        let match_types = |cli: &Cli, source: Option<&str>, body: &str| {
+            find_matches(cli, &cli.search_term, source, &cache(source, body))
+                .unwrap()
+                .into_iter()
+                .map(|found| (found.name, found.kind))
+                .collect::<Vec<(String, MatchKind)>>()
+        };
 
-    let cache_file = PathBuf::from(DEFAULTS.cache_file);
-    let experimental_cache_file = PathBuf::from(DEFAULTS.experimental_cache_file);
+        let cli = Cli::try_parse_from(vec!["nps", "-e=false", "mypackage"]).unwrap();
+        assert_eq!(
+            match_types(
+                &cli,
+                None,
+                "mypackage\t1\t\nnixpkgs.mypackage_2\t1\t\nnixos.MyPackage\t1\t"
+            ),
+            vec![
+                ("nixos.MyPackage".to_string(), MatchKind::Exact),
+                ("nixpkgs.mypackage_2".to_string(), MatchKind::Direct),
+                ("mypackage".to_string(), MatchKind::Indirect),
+            ]
+        );
 
-    log::trace!("cache_file: {:?}", cache_file);
-    log::trace!("experimental_cache_file: {:?}", experimental_cache_file);
+        let cli_experimental = Cli::try_parse_from(vec!["nps", "-e=true", "mypackage"]).unwrap();
+        assert_eq!(
+            match_types(
+                &cli_experimental,
+                Some("nixpkgs"),
+                "nixos.mypackage\t1\t\nmypackage_2\t1\t\nmypackage\t1\t"
+            ),
+            vec![
+                ("mypackage".to_string(), MatchKind::Exact),
+                ("mypackage_2".to_string(), MatchKind::Direct),
+                ("nixos.mypackage".to_string(), MatchKind::Indirect),
+            ]
+        );
 
-    let file_path: PathBuf = match cli.experimental {
-        true => cli.cache_folder.join(&experimental_cache_file),
-        false => cli.cache_folder.join(&cache_file),
-    };
+        let cli_fuzzy = Cli::try_parse_from(vec!["nps", "--fuzzy", "neovmi"]).unwrap();
+        assert_eq!(
+            match_types(
+                &cli_fuzzy,
+                None,
+                "nixpkgs.neomutt-vim-ide\t1\t\nnixpkgs.emacs\t1\t\n\
+                nixpkgs.neovim-qt\t1\t\nnixos.neovim\t1\t"
+            ),
+            vec![
+                ("nixos.neovim".to_string(), MatchKind::Exact),
+                ("nixpkgs.neovim-qt".to_string(), MatchKind::Direct),
+                ("nixpkgs.neomutt-vim-ide".to_string(), MatchKind::Indirect),
+            ]
+        );
+    }
 
-    log::trace!("file_path: {:?}", file_path);
+    #[test]
+    fn test_format_matches() {
+        init();
 
-    let cache_file_exists = file_path.exists();
+        let cli = |columns: &str| {
+            Cli::try_parse_from(vec!["nps", "-e=true", "--flip", columns, "mypackage"]).unwrap()
+        };
+        let contents = vec![(
+            Some("nixpkgs"),
+            cache(
+                Some("nixpkgs"),
+                "\
+                mypackage\tv1\tmy package description\n\
+                myotherpackage\tv2\tlike mypackage\n\
+                mypackage_extension\tv3\twords words\n\
+                mypackage_extension_2\tv4\twords words w0rds\n\
+                mylastpackage\tv5.0.0\tis not mypackage\
+                ",
+            ),
+        )];
 
-    log::trace!("cache_file_exists: {}", cache_file_exists);
-    log::trace!("cli.refresh: {}", cli.refresh);
+        let format = |cli: &Cli| {
+            let matches =
+                search(cli, &SearchContext::default(), &cli.search_term, &contents).unwrap();
+            format_matches(cli, &matches, termcolor::ColorChoice::Never).unwrap()
+        };
 
-    // Refresh cache with new info?
-    if cli.refresh || !cache_file_exists {
-        log::trace!("inside if");
-        match refresh(cli.experimental, &file_path, cli.quiet) {
-            Ok(_) => {
-                if cli.refresh {
-                    return ExitCode::SUCCESS;
-                }
-            }
-            Err(err) => {
-                log::error!("Can't refresh cache: {err}");
-                return ExitCode::FAILURE;
-            }
-        }
-    }
+        assert_eq!(
+            format(&cli("-C=all")),
+            "\
+mypackage              v1      my package description
 
-    let content = match fs::read_to_string(&file_path) {
-        Ok(content) => content,
-        Err(err) => {
-            log::error!("Can't open file {}: {err}", &file_path.display());
-            return ExitCode::FAILURE;
-        }
-    };
+mypackage_extension    v3      words words
+mypackage_extension_2  v4      words words w0rds
 
-    let raw_matches = match get_matches(&cli, &content) {
-        Ok(raw_matches) => raw_matches,
-        Err(err) => {
-            log::error!("Can't get matches: {err}");
-            return ExitCode::FAILURE;
-        }
-    };
-    if raw_matches.is_empty() {
-        return ExitCode::FAILURE;
-    }
+myotherpackage         v2      like mypackage
+mylastpackage          v5.0.0  is not mypackage"
+        );
+        assert_eq!(
+            format(&cli("-C=none")),
+            "\
+mypackage \n\
+\n\
+mypackage_extension \n\
+mypackage_extension_2 \n\
+\n\
+myotherpackage \n\
+mylastpackage"
+        );
+        assert_eq!(
+            format(&cli("-C=version")),
+            "\
+mypackage              v1
 
-    let sorted_padded_matches = match sort_and_pad_matches(&cli, raw_matches) {
-        Ok(sorted_padded_matches) => sorted_padded_matches,
-        Err(err) => {
-            log::error!("Can't sort matches: {err}");
-            return ExitCode::FAILURE;
-        }
-    };
+mypackage_extension    v3
+mypackage_extension_2  v4
 
-    let colored_matches = match color_matches(&cli, sorted_padded_matches, color_choice) {
-        Ok(colored_matches) => colored_matches,
-        Err(err) => {
-            log::error!("Can't color matches: {err}");
-            return ExitCode::FAILURE;
-        }
-    };
+myotherpackage         v2
+mylastpackage          v5.0.0"
+        );
+        assert_eq!(
+            format(&cli("-C=description")),
+            "\
+mypackage              my package description
 
-    if let Err(err) = print_matches(&cli, colored_matches) {
-        log::error!("Can't print matches: {err}");
-        return ExitCode::FAILURE;
-    }
+mypackage_extension    words words
+mypackage_extension_2  words words w0rds
 
-    ExitCode::SUCCESS
-}
+myotherpackage         like mypackage
+mylastpackage          is not mypackage"
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        // Merged matches are annotated with their sources
+        let merged_cli = Cli::try_parse_from(vec!["nps", "-m", "--flip", "mypackage"]).unwrap();
+        let merged_contents = vec![
+            (
+                None,
+                cache(None, "nixos.mypackage\tv1\tmy package description"),
+            ),
+            (
+                Some("nixpkgs"),
+                cache(
+                    Some("nixpkgs"),
+                    "mypackage\tv1\tmy package description\n\
+                    mylastpackage\tv5.0.0\tis not mypackage",
+                ),
+            ),
+        ];
+        let matches = search(
+            &merged_cli,
+            &SearchContext::default(),
+            &merged_cli.search_term,
+            &merged_contents,
+        )
+        .unwrap();
+        assert_eq!(
+            format_matches(&merged_cli, &matches, termcolor::ColorChoice::Never).unwrap(),
+            "\
+mypackage      v1      [channel:nixos,nixpkgs]  my package description
 
-    fn init() {
-        let _ = env_logger::builder().is_test(true).try_init();
+mylastpackage  v5.0.0  [nixpkgs]                is not mypackage"
+        );
+
+        // Columns are padded by characters, not bytes
+        let unicode_cli = cli("-C=all");
+        let unicode_contents = vec![(
+            Some("nixpkgs"),
+            cache(
+                Some("nixpkgs"),
+                "mypackage-ü\tv1\tumlaut\nmypackage\tv2→3\tarrow",
+            ),
+        )];
+        let matches = search(
+            &unicode_cli,
+            &SearchContext::default(),
+            &unicode_cli.search_term,
+            &unicode_contents,
+        )
+        .unwrap();
+        assert_eq!(
+            format_matches(&unicode_cli, &matches, termcolor::ColorChoice::Never).unwrap(),
+            "\
+mypackage    v2→3  arrow
+
+mypackage-ü  v1    umlaut"
+        );
     }
 
     #[test]
-    fn test_get_matches() {
+    fn test_bin_match_type() {
         init();
 
-        let cli = Cli::try_parse_from(vec!["nps", "second"]).unwrap();
-        let content = "\
-            the first line\n\
-            the second line\n\
-            the third line\
-            ";
-        let matches = get_matches(&cli, content).unwrap();
+        let with_program = PackageMeta {
+            pname: Some("ripgrep".to_string()),
+            main_programs: vec!["rg".to_string()],
+            ..Default::default()
+        };
+        let without_program = PackageMeta {
+            pname: Some("rg".to_string()),
+            ..Default::default()
+        };
 
-        assert_eq!(matches, "the second line\n");
+        assert_eq!(
+            bin_match_type("rg", &with_program, false),
+            Some(MatchKind::Exact)
+        );
+        assert_eq!(
+            bin_match_type("rg", &without_program, false),
+            Some(MatchKind::Direct)
+        );
+        assert_eq!(
+            bin_match_type("grep", &with_program, false),
+            Some(MatchKind::Indirect)
+        );
+        assert_eq!(bin_match_type("RG", &with_program, false), None);
+        assert_eq!(
+            bin_match_type("RG", &with_program, true),
+            Some(MatchKind::Exact)
+        );
+        assert_eq!(bin_match_type("fd", &with_program, false), None);
     }
 
     #[test]
-    fn test_convert_case() {
+    fn test_passes_filters() {
         init();
 
-        let test_string = "abCDef";
-
-        assert_eq!(convert_case(test_string, false), "abCDef");
-        assert_eq!(convert_case(test_string, true), "abcdef");
+        let mut cli = Cli::try_parse_from(vec!["nps", "hello"]).unwrap();
+        let meta = PackageMeta {
+            licenses: vec!["MIT".to_string()],
+            platforms: vec!["x86_64-linux".to_string()],
+            unfree: true,
+            evaluated: true,
+            ..Default::default()
+        };
+        assert!(passes_filters(&cli, &meta));
+
+        cli.license = vec!["mit".to_string(), "apache-2.0".to_string()];
+        assert!(passes_filters(&cli, &meta));
+        cli.license = vec!["gpl-3.0-only".to_string()];
+        assert!(!passes_filters(&cli, &meta));
+        cli.license = vec![];
+
+        cli.platform = Some("x86_64-linux".to_string());
+        assert!(passes_filters(&cli, &meta));
+        cli.platform = Some("aarch64-linux".to_string());
+        assert!(!passes_filters(&cli, &meta));
+        cli.platform = None;
+
+        cli.no_broken = true;
+        cli.no_insecure = true;
+        assert!(passes_filters(&cli, &meta));
+        cli.no_unfree = true;
+        assert!(!passes_filters(&cli, &meta));
+
+        // Packages whose metadata wasn't evaluated can't be told apart
+        cli.no_unfree = false;
+        let unevaluated = PackageMeta {
+            pname: Some("hello".to_string()),
+            ..Default::default()
+        };
+        assert!(!passes_filters(&cli, &unevaluated));
+        let meta_of = |name: &str, package_meta| (name.to_string(), package_meta);
+        let all_meta = HashMap::from([meta_of("evaluated", meta), meta_of("hello", unevaluated)]);
+        assert_eq!(
+            allowed_packages(&cli, &all_meta),
+            HashSet::from(["evaluated".to_string()])
+        );
     }
 
     #[test]
-    fn test_sort_and_pad_matches() {
+    fn test_cache_changes() {
         init();
 
-        let cli_all_columns = Cli::try_parse_from(vec!["nps", "-e=true", "mypackage"]).unwrap();
-        let cli_no_other_columns =
-            Cli::try_parse_from(vec!["nps", "-e=true", "-C=none", "mypackage"]).unwrap();
-        let cli_version_column =
-            Cli::try_parse_from(vec!["nps", "-e=true", "-C=version", "mypackage"]).unwrap();
-        let cli_description_column =
-            Cli::try_parse_from(vec!["nps", "-e=true", "-C=description", "mypackage"]).unwrap();
-        let matches = "\
-            mypackage v1 my package description\n\
-            myotherpackage v2 has description as well\n\
-            mypackage_extension v3 words words\n\
-            mypackage_extension_2 v4 words words w0rds\n\
-            mylastpackage v5.0.0 is not mypackage\
-            "
-        .to_string();
-
-        let exact_matches_all_columns = "\
-            mypackage              v1      my package description\
-            ";
-        let direct_matches_all_columns = "\
-            mypackage_extension    v3      words words\n\
-            mypackage_extension_2  v4      words words w0rds\
-            ";
-        let indirect_matches_all_columns = "\
-            myotherpackage         v2      has description as well\n\
-            mylastpackage          v5.0.0  is not mypackage\
-            ";
-
-        let exact_matches_no_other_columns = "\
-            mypackage \
-            ";
-        let direct_matches_no_other_columns = "\
-            mypackage_extension \n\
-            mypackage_extension_2 \
-            ";
-        let indirect_matches_no_other_columns = "\
-            myotherpackage \n\
-            mylastpackage \
-            ";
-
-        let exact_matches_version_column = "\
-            mypackage              v1\
-            ";
-        let direct_matches_version_column = "\
-            mypackage_extension    v3\n\
-            mypackage_extension_2  v4\
-            ";
-        let indirect_matches_version_column = "\
-            myotherpackage         v2\n\
-            mylastpackage          v5.0.0\
-            ";
-
-        let exact_matches_description_column = "\
-            mypackage              my package description\
-            ";
-        let direct_matches_description_column = "\
-            mypackage_extension    words words\n\
-            mypackage_extension_2  words words w0rds\
-            ";
-        let indirect_matches_description_column = "\
-            myotherpackage         has description as well\n\
-            mylastpackage          is not mypackage\
-            ";
-
-        let sorted_and_padded_all_columns =
-            sort_and_pad_matches(&cli_all_columns, matches.clone()).unwrap();
-        let sorted_and_padded_no_other_columns =
-            sort_and_pad_matches(&cli_no_other_columns, matches.clone()).unwrap();
-        let sorted_and_padded_version_column =
-            sort_and_pad_matches(&cli_version_column, matches.clone()).unwrap();
-        let sorted_and_padded_description_column =
-            sort_and_pad_matches(&cli_description_column, matches).unwrap();
-
-        assert_eq!(
-            exact_matches_all_columns,
-            sorted_and_padded_all_columns.0.join("\n")
+        let previous = cache(
+            Some("nixpkgs"),
+            "\
+            kept\t1.0\tunchanged\n\
+            removed\t0.1\tgone now\n\
+            updated\t2.0\tnew version",
         );
-        assert_eq!(
-            direct_matches_all_columns,
-            sorted_and_padded_all_columns.1.join("\n")
+        let current = cache(
+            Some("nixpkgs"),
+            "\
+            added\t\tno version yet\n\
+            kept\t1.0\tunchanged\n\
+            updated\t2.1\tnew version",
         );
+        let changes = current.changes(&previous);
+        assert_eq!(changes.flake, Some("nixpkgs"));
         assert_eq!(
-            indirect_matches_all_columns,
-            sorted_and_padded_all_columns.2.join("\n")
+            changes.added,
+            vec![ChangedPackage {
+                name: "added",
+                old_version: None,
+                new_version: Some(""),
+            }]
         );
-
         assert_eq!(
-            exact_matches_no_other_columns,
-            sorted_and_padded_no_other_columns.0.join("\n")
+            changes.removed,
+            vec![ChangedPackage {
+                name: "removed",
+                old_version: Some("0.1"),
+                new_version: None,
+            }]
         );
         assert_eq!(
-            direct_matches_no_other_columns,
-            sorted_and_padded_no_other_columns.1.join("\n")
+            changes.updated,
+            vec![ChangedPackage {
+                name: "updated",
+                old_version: Some("2.0"),
+                new_version: Some("2.1"),
+            }]
         );
+
+        let changes = CacheChanges {
+            previous_created: 0,
+            created: 86_400,
+            ..changes
+        };
         assert_eq!(
-            indirect_matches_no_other_columns,
-            sorted_and_padded_no_other_columns.2.join("\n")
+            format_changes(&changes, 7 * 86_400),
+            "\
+Changes in nixpkgs since the refresh 7 days ago:
+Added (1):
+  added
+Removed (1):
+  removed  0.1
+Updated (1):
+  updated  2.0 -> 2.1"
         );
 
+        let no_changes = CacheChanges::default();
         assert_eq!(
-            exact_matches_version_column,
-            sorted_and_padded_version_column.0.join("\n")
+            format_changes(&no_changes, 60),
+            "No changes in channels since the refresh 1 minute ago"
         );
+    }
+
+    #[test]
+    fn test_format_info() {
+        init();
+
+        let mut info = PackageInfo {
+            name: "ripgrep",
+            flake: Some("nixpkgs"),
+            version: "14.1.1",
+            description: "Fast grep",
+            meta: None,
+        };
         assert_eq!(
-            direct_matches_version_column,
-            sorted_and_padded_version_column.1.join("\n")
+            format_info(&info),
+            "\
+nixpkgs#ripgrep
+  version:      14.1.1
+  description:  Fast grep"
         );
+
+        info.meta = Some(PackageMeta {
+            main_programs: vec!["rg".to_string()],
+            licenses: vec!["MIT".to_string(), "Unlicense".to_string()],
+            broken: true,
+            evaluated: true,
+            ..Default::default()
+        });
         assert_eq!(
-            indirect_matches_version_column,
-            sorted_and_padded_version_column.2.join("\n")
+            format_info(&info),
+            "\
+nixpkgs#ripgrep
+  version:      14.1.1
+  description:  Fast grep
+  main program: rg
+  licenses:     MIT, Unlicense
+  broken:       yes
+  unfree:       no
+  insecure:     no"
         );
+    }
+
+    #[test]
+    fn test_merge_matches() {
+        init();
+
+        let package = |name: &str, version: &str, description: &str| Match {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: description.to_string(),
+            kind: MatchKind::Indirect,
+            highlights: Highlights::default(),
+        };
+        let mut channel_package = package("nixpkgs.mypackage", "v1", "my package description");
+        channel_package.highlights.name = vec![0..2, 8..17];
+        let channel_matches = vec![
+            channel_package,
+            package("nixos.mypackage", "v1", "my package description"),
+            package("nixos.zpackage", "v2", "last package"),
+        ];
+        let flake_matches = vec![
+            package("apackage", "v3", "first package"),
+            package("mypackage", "v4", "my newer package description"),
+        ];
+        let desired_output = vec![
+            "apackage\tv3\tfirst package\tnixpkgs",
+            "mypackage\tv1\tmy package description\tchannel:nixpkgs,channel:nixos,nixpkgs",
+            "zpackage\tv2\tlast package\tchannel:nixos",
+        ];
+
+        let merged = merge_matches(vec![
+            (None, channel_matches),
+            (Some("nixpkgs"), flake_matches),
+        ]);
+        let lines: Vec<String> = merged
+            .iter()
+            .map(|found| {
+                format!(
+                    "{}\t{}\t{}\t{}",
+                    found.package.name,
+                    found.package.version,
+                    found.package.description,
+                    found.sources.as_deref().unwrap_or_default().join(",")
+                )
+            })
+            .collect();
+        assert_eq!(lines, desired_output);
+        // Highlights of stripped channel names are moved along
+        assert_eq!(merged[1].package.highlights.name, vec![0..9]);
+    }
+
+    #[test]
+    fn test_select_cache_folders() {
+        init();
 
+        let own_folder = tempfile::TempDir::new().unwrap();
+        let system_folder = tempfile::TempDir::new().unwrap();
+        let body = "hello\t1.0\tGreets";
+        write_cache(own_folder.path(), None, 1736899200, body);
+        write_cache(system_folder.path(), None, 1736294400, body);
+        write_cache(system_folder.path(), Some("nixpkgs"), 1736294400, body);
+
+        let cli = Cli::try_parse_from(vec![
+            "nps".to_string(),
+            format!("--cache-folder={}", own_folder.path().display()),
+            format!("--system-cache-folder={}", system_folder.path().display()),
+            "hello".to_string(),
+        ])
+        .unwrap();
+
+        // The own channels cache is fresher, there is only a system flake cache
+        let cache_folders = select_cache_folders(&cli, &[None, Some("nixpkgs")]).unwrap();
         assert_eq!(
-            exact_matches_description_column,
-            sorted_and_padded_description_column.0.join("\n")
+            cache_folders,
+            HashMap::from([(
+                Some("nixpkgs".to_string()),
+                system_folder.path().to_path_buf()
+            )])
         );
+
+        write_cache(system_folder.path(), None, 1737504000, body);
+        let cache_folders = select_cache_folders(&cli, &[None]).unwrap();
         assert_eq!(
-            direct_matches_description_column,
-            sorted_and_padded_description_column.1.join("\n")
+            cache_folders,
+            HashMap::from([(None, system_folder.path().to_path_buf())])
         );
+
+        let context = SearchContext {
+            cache_folders,
+            ..Default::default()
+        };
+        assert_eq!(cache_folder(&cli, &context, None), system_folder.path());
         assert_eq!(
-            indirect_matches_description_column,
-            sorted_and_padded_description_column.2.join("\n")
+            cache_folder(&cli, &context, Some("nixpkgs")),
+            own_folder.path()
         );
+
+        // Refreshes of the system cache only use the system cache
+        let mut cli = cli;
+        cli.system = true;
+        assert!(select_cache_folders(&cli, &[None]).unwrap().is_empty());
+        assert_eq!(own_cache_folder(&cli), system_folder.path());
     }
 
     #[test]
-    fn test_parse_json_to_lines() -> Result<(), Box<dyn Error>> {
+    fn test_migrate_cache_folder() {
         init();
 
-        let json = "{\
-            \"legacyPackages.x86_64-linux.mypackage\": {\
-            \"description\":\"i describe\",\
-            \"pname\":\"mypackagebinary\",\
-            \"version\":\"old\"},\
-            \
-            \"legacyPackages.x86_64-linux.myotherpackage\": {\
-            \"description\":\"i also describe\",\
-            \"pname\":\"myotherpackagebinary\",\
-            \"version\":\"fresh\"}\
-            }";
-        let desired_output = "\
-            myotherpackage fresh i also describe\n\
-            mypackage old i describe\
-            ";
-        let parsed = parse_json_to_lines(json)?;
-
-        assert_eq!(parsed, desired_output);
-        Ok(())
+        let home = tempfile::TempDir::new().unwrap();
+        let legacy_folder = home.path().join(".nix-package-search");
+        let cache_folder = home.path().join(".cache").join("nps");
+        fs::create_dir(&legacy_folder).unwrap();
+        fs::write(legacy_folder.join("nps.cache"), "channels").unwrap();
+        fs::write(legacy_folder.join("nps.experimental.cache"), "flakes").unwrap();
+
+        assert!(migrate_cache_folder(&legacy_folder, &cache_folder).unwrap());
+        assert!(!legacy_folder.exists());
+        assert_eq!(
+            fs::read_to_string(cache_folder.join("nps.cache")).unwrap(),
+            "channels"
+        );
+        assert_eq!(
+            fs::read_to_string(cache_folder.join("nps.experimental.cache")).unwrap(),
+            "flakes"
+        );
+
+        // Nothing left to move
+        assert!(!migrate_cache_folder(&legacy_folder, &cache_folder).unwrap());
+
+        // Caches in the new folder are kept
+        fs::create_dir(&legacy_folder).unwrap();
+        fs::write(legacy_folder.join("nps.cache"), "old channels").unwrap();
+        fs::write(legacy_folder.join("nps.cache.previous"), "older channels").unwrap();
+        assert!(migrate_cache_folder(&legacy_folder, &cache_folder).unwrap());
+        assert!(!legacy_folder.exists());
+        assert_eq!(
+            fs::read_to_string(cache_folder.join("nps.cache")).unwrap(),
+            "channels"
+        );
+        assert_eq!(
+            fs::read_to_string(cache_folder.join("nps.cache.previous")).unwrap(),
+            "older channels"
+        );
     }
 
     #[test]
-    fn test_color_matches() {
+    fn test_parse_cli() {
         init();
 
-        let cli = Cli::try_parse_from(vec!["nps", "-e=true", "mypackage"]).unwrap();
-        let exact_matches = vec!["mypackage             v1     my package description".to_string()];
-        let direct_matches = vec![
-            "mypackage_extension   v1     my package description".to_string(),
-            "mypackage_extension_2 v1.0.1 my package description".to_string(),
-        ];
-        let indirect_matches = vec![
-            "mylastpackage         v5.0.0 is not mypackage".to_string(),
-            "mylastpackage_2       v1     is not mypackage either".to_string(),
-        ];
+        let config_home = tempfile::TempDir::new().unwrap();
+        let config_path = config_home.path().join("config.toml");
+        fs::write(
+            &config_path,
+            r#"
+flip = true
+sort = "version"
+flakes = ["nixpkgs", "github:nix-community/NUR"]
+
+[profile.work]
+sort = "name"
+exact_color = "red"
+"#,
+        )
+        .unwrap();
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<OsString>>();
+        let setting = |settings: &[Setting], key: &str| {
+            settings
+                .iter()
+                .find(|setting| setting.key == key)
+                .cloned()
+                .unwrap()
+        };
 
-        let expect_color = [
-            "\u{1b}[0m\u{1b}[1m\u{1b}[35mmypackage\u{1b}[0m             v1     my package description\n",
-            "\u{1b}[0m\u{1b}[1m\u{1b}[34mmypackage\u{1b}[0m_extension_2 v1.0.1 my package description\n\
-                \u{1b}[0m\u{1b}[1m\u{1b}[34mmypackage\u{1b}[0m_extension   v1     my package description\n",
-            "mylastpackage_2       v1     is not \u{1b}[0m\u{1b}[1m\u{1b}[32mmypackage\u{1b}[0m either\n\
-                mylastpackage         v5.0.0 is not \u{1b}[0m\u{1b}[1m\u{1b}[32mmypackage\u{1b}[0m\n",
-        ];
-        let expect_no_color = [
-            "mypackage             v1     my package description\n",
-            "mypackage_extension_2 v1.0.1 my package description\n\
-                mypackage_extension   v1     my package description\n",
-            "mylastpackage_2       v1     is not mypackage either\n\
-                mylastpackage         v5.0.0 is not mypackage\n",
-        ];
+        temp_env::with_var("NIX_PACKAGE_SEARCH_FLIP", None::<&str>, || {
+            let (cli, settings) = parse_cli(args(&["nps", "neovim"]), &config_path).unwrap();
+            assert!(cli.flip);
+            assert!(matches!(cli.sort, SortChoice::Version));
+            assert_eq!(cli.flake, vec!["nixpkgs", "github:nix-community/NUR"]);
+            assert_eq!(
+                setting(&settings, "flip").source,
+                SettingSource::ConfigFile(config_path.clone())
+            );
+            assert_eq!(
+                setting(&settings, "flakes").value,
+                Some(toml::Value::Array(vec![
+                    "nixpkgs".into(),
+                    "github:nix-community/NUR".into()
+                ]))
+            );
+            assert_eq!(setting(&settings, "columns").source, SettingSource::Default);
+            assert_eq!(setting(&settings, "max_cache_age").value, None);
+
+            // Profiles override the top level of the config file
+            let (cli, settings) =
+                parse_cli(args(&["nps", "--profile=work", "neovim"]), &config_path).unwrap();
+            assert!(matches!(cli.sort, SortChoice::Name));
+            assert!(matches!(cli.exact_color, Colors::Red));
+            assert_eq!(
+                setting(&settings, "sort").source,
+                SettingSource::Profile("work".to_string())
+            );
 
-        let matches = (exact_matches, direct_matches, indirect_matches);
+            // The command line overrides everything
+            let (cli, settings) = parse_cli(
+                args(&["nps", "--profile=work", "--sort=version", "neovim"]),
+                &config_path,
+            )
+            .unwrap();
+            assert!(matches!(cli.sort, SortChoice::Version));
+            assert_eq!(
+                setting(&settings, "sort").source,
+                SettingSource::CommandLine
+            );
 
-        let colored_matches_color =
-            color_matches(&cli, matches.clone(), termcolor::ColorChoice::Always).unwrap();
-        let colored_matches_no_color =
-            color_matches(&cli, matches, termcolor::ColorChoice::Never).unwrap();
+            assert!(parse_cli(args(&["nps", "--profile=home", "neovim"]), &config_path).is_err());
+
+            // An empty flake reference clears the flakes of the config file,
+            // background refreshes of channels rely on it
+            let (cli, _) = parse_cli(
+                args(&["nps", "--flake=", "--merge=false", "--refresh"]),
+                &config_path,
+            )
+            .unwrap();
+            assert_eq!(sources(&cli), vec![None]);
+
+            // A missing config file has no settings
+            let (cli, _) = parse_cli(
+                args(&["nps", "neovim"]),
+                &config_home.path().join("missing.toml"),
+            )
+            .unwrap();
+            assert!(!cli.flip);
+        });
 
-        for (expect, output) in std::iter::zip(
-            [expect_color, expect_no_color].concat(),
-            [colored_matches_color, colored_matches_no_color].concat(),
-        ) {
-            assert_eq!(expect, String::from_utf8(output.into_inner()).unwrap());
-        }
+        // Environment variables override the config file
+        temp_env::with_var("NIX_PACKAGE_SEARCH_FLIP", Some("false"), || {
+            let (cli, settings) = parse_cli(args(&["nps", "neovim"]), &config_path).unwrap();
+            assert!(!cli.flip);
+            assert_eq!(
+                setting(&settings, "flip").source,
+                SettingSource::Environment("NIX_PACKAGE_SEARCH_FLIP".to_string())
+            );
+        });
+
+        fs::write(&config_path, "flip = true\nflipp = true\n").unwrap();
+        let err = parse_cli(args(&["nps", "neovim"]), &config_path).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown setting `flipp`"));
+
+        // Metadata filters are settings, one-off options aren't
+        fs::write(
+            &config_path,
+            "license = [\"mit\", \"apache-2.0\"]\nplatform = \"aarch64-linux\"\nno_unfree = true\n",
+        )
+        .unwrap();
+        let (cli, settings) = parse_cli(args(&["nps", "neovim"]), &config_path).unwrap();
+        assert_eq!(cli.license, vec!["mit", "apache-2.0"]);
+        assert_eq!(cli.platform.as_deref(), Some("aarch64-linux"));
+        assert!(cli.no_unfree && !cli.no_broken);
+        assert_eq!(
+            setting(&settings, "no_unfree").source,
+            SettingSource::ConfigFile(config_path.clone())
+        );
+        let (cli, _) =
+            parse_cli(args(&["nps", "--no-unfree=false", "neovim"]), &config_path).unwrap();
+        assert!(!cli.no_unfree);
+
+        fs::write(&config_path, "bin = \"rg\"\n").unwrap();
+        let err = parse_cli(args(&["nps", "neovim"]), &config_path).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown setting `bin`"));
+
+        fs::write(&config_path, "flip = \"sometimes\"\n").unwrap();
+        let err = parse_cli(args(&["nps", "neovim"]), &config_path).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid setting in config file"));
     }
 
     #[test]
-    fn test_check_flakes_enabled() {
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_color_matches() {
         init();
 
-        // Create a temporary directory for a nix.conf file
-        let tempdir = tempfile::TempDir::new().unwrap();
-        let nix_conf_dir = &tempdir.path().join("nix");
-        fs::create_dir_all(nix_conf_dir).unwrap();
+        let cli = Cli::try_parse_from(vec!["nps", "-e=true", "mypackage"]).unwrap();
+        let contents = vec![(
+            Some("nixpkgs"),
+            cache(
+                Some("nixpkgs"),
+                "\
+                mypackage\tv1\tmy package description\n\
+                mypackage_extension\tv1\tmy package description\n\
+                mylastpackage\tv5.0.0\tis not mypackage\
+                ",
+            ),
+        )];
+        let matches = search(&cli, &SearchContext::default(), &cli.search_term, &contents).unwrap();
+        assert_eq!(
+            matches[0].package.highlights,
+            Highlights {
+                name: vec![0..9],
+                ..Default::default()
+            }
+        );
+        assert_eq!(matches[2].package.highlights.description, vec![7..16]);
+
+        let expect_color = "\
+mylastpackage        v5.0.0  is not \u{1b}[0m\u{1b}[1m\u{1b}[32mmypackage\u{1b}[0m
 
-        let tempfile = NamedTempFile::new_in(&tempdir).unwrap();
-        // Enable experimental features: "nix-command" and "flakes"
-        write!(&tempfile, "experimental-features = nix-command flakes").unwrap();
-        tempfile.persist(nix_conf_dir.join("nix.conf")).unwrap();
+\u{1b}[0m\u{1b}[1m\u{1b}[34mmypackage\u{1b}[0m_extension  v1      my package description
 
-        temp_env::with_var("XDG_CONFIG_HOME", Some(&tempdir.path()), || {
-            assert!(check_flakes_enabled().unwrap())
-        });
+\u{1b}[0m\u{1b}[1m\u{1b}[35mmypackage\u{1b}[0m            v1      my package description";
+        let expect_no_color = "\
+mylastpackage        v5.0.0  is not mypackage
 
-        let tempfile = NamedTempFile::new_in(&tempdir).unwrap();
-        // Disable all experimental features
-        write!(&tempfile, "experimental-features = ").unwrap();
-        tempfile.persist(nix_conf_dir.join("nix.conf")).unwrap();
+mypackage_extension  v1      my package description
 
-        temp_env::with_var("XDG_CONFIG_HOME", Some(&tempdir.path()), || {
-            assert!(!check_flakes_enabled().unwrap())
-        });
+mypackage            v1      my package description";
+
+        assert_eq!(
+            format_matches(&cli, &matches, termcolor::ColorChoice::Always).unwrap(),
+            expect_color
+        );
+        assert_eq!(
+            format_matches(&cli, &matches, termcolor::ColorChoice::Never).unwrap(),
+            expect_no_color
+        );
     }
 }
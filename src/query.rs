@@ -0,0 +1,434 @@
+//! Search terms and how they are matched against cache lines
+
+use grep::{
+    matcher::Matcher,
+    regex::{RegexMatcher, RegexMatcherBuilder},
+};
+use serde::Serialize;
+use std::{error::Error, fmt, ops::Range, str::FromStr};
+
+/// Column of the package table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Name,
+    Version,
+    Description,
+}
+
+/// Search term, optionally restricted to one column
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryTerm {
+    pub field: Option<Field>,
+    pub pattern: String,
+    /// The pattern doesn't look like a regex, search for it literally
+    pub literal: bool,
+}
+
+impl QueryTerm {
+    /// Regex to search for, escaped if the pattern is meant literally
+    pub fn regex(&self, fixed_strings: bool) -> String {
+        match fixed_strings || self.literal {
+            true => regex::escape(&self.pattern),
+            false => self.pattern.clone(),
+        }
+    }
+
+    /// Name or name prefix to look up in the name index, if the term is
+    /// `name:^PREFIX` or `name:^NAME$` without other regex syntax
+    pub(crate) fn name_lookup(&self, fixed_strings: bool) -> Option<(&str, bool)> {
+        if self.field != Some(Field::Name) || self.literal || fixed_strings {
+            return None;
+        }
+        let pattern = self.pattern.strip_prefix('^')?;
+        let (name, prefix) = match pattern.strip_suffix('$') {
+            Some(name) => (name, false),
+            None => (pattern, true),
+        };
+        match name.is_empty() || name.contains(|character| "\\.+*?()|[]{}^$".contains(character)) {
+            true => None,
+            false => Some((name, prefix)),
+        }
+    }
+}
+
+impl fmt::Display for QueryTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.field {
+            Some(Field::Name) => write!(f, "name:{}", self.pattern),
+            Some(Field::Version) => write!(f, "version:{}", self.pattern),
+            Some(Field::Description) => write!(f, "desc:{}", self.pattern),
+            None => write!(f, "{}", self.pattern),
+        }
+    }
+}
+
+impl FromStr for QueryTerm {
+    type Err = String;
+
+    /// Parse a search term like `name:^neovim`, see `parse_query_term`
+    fn from_str(term: &str) -> Result<Self, Self::Err> {
+        parse_query_term(term)
+    }
+}
+
+/// Byte ranges of search term matches in the columns of a package
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Highlights {
+    pub name: Vec<Range<usize>>,
+    pub version: Vec<Range<usize>>,
+    pub description: Vec<Range<usize>>,
+}
+
+impl Highlights {
+    fn column_mut(&mut self, field: Field) -> &mut Vec<Range<usize>> {
+        match field {
+            Field::Name => &mut self.name,
+            Field::Version => &mut self.version,
+            Field::Description => &mut self.description,
+        }
+    }
+}
+
+/// Sort ranges and merge overlapping or adjacent ones
+fn merge_ranges(ranges: &mut Vec<Range<usize>>) {
+    ranges.sort_by_key(|range| (range.start, range.end));
+    let mut merged: Vec<Range<usize>> = vec![];
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
+/// How search terms are combined and matched
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct QueryOptions {
+    /// Match any search term instead of all of them
+    pub any: bool,
+    pub ignore_case: bool,
+    /// Search for all terms literally, not as regexes
+    pub fixed_strings: bool,
+}
+
+/// Compiled search terms, evaluated per column of a cache line
+#[derive(Debug)]
+pub struct Query {
+    terms: Vec<QueryTerm>,
+    matchers: Vec<RegexMatcher>,
+    options: QueryOptions,
+}
+
+impl Query {
+    /// Compile the search terms
+    pub fn new(terms: Vec<QueryTerm>, options: QueryOptions) -> Result<Self, Box<dyn Error>> {
+        let mut matchers = vec![];
+        for term in terms.iter() {
+            let matcher = RegexMatcherBuilder::new()
+                .case_insensitive(options.ignore_case)
+                .build(&term.regex(options.fixed_strings))
+                .map_err(|err| format!("Can't build regex: {err}"))?;
+            matchers.push(matcher);
+        }
+        Ok(Query {
+            terms,
+            matchers,
+            options,
+        })
+    }
+
+    /// Parse and compile search terms like `name:^neovim`
+    pub fn parse<S: AsRef<str>>(
+        terms: &[S],
+        options: QueryOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let terms = terms
+            .iter()
+            .map(|term| parse_query_term(term.as_ref()))
+            .collect::<Result<Vec<QueryTerm>, String>>()?;
+        Query::new(terms, options)
+    }
+
+    pub fn terms(&self) -> &[QueryTerm] {
+        &self.terms
+    }
+
+    pub fn options(&self) -> QueryOptions {
+        self.options
+    }
+
+    /// The search term matches are sorted by, see `primary_term`
+    pub fn primary_term(&self) -> Option<&QueryTerm> {
+        primary_term(&self.terms)
+    }
+
+    /// Check if a cache line `NAME\tVERSION\tDESCRIPTION` matches all terms,
+    /// or any of them
+    pub fn is_match(&self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let fields: Vec<&str> = line.splitn(3, '\t').collect();
+        for (term, matcher) in self.terms.iter().zip(self.matchers.iter()) {
+            let haystack = match term.field {
+                Some(Field::Name) => fields.first(),
+                Some(Field::Version) => fields.get(1),
+                Some(Field::Description) => fields.get(2),
+                None => Some(&line),
+            };
+            let is_match = matcher
+                .is_match(haystack.unwrap_or(&"").as_bytes())
+                .map_err(|err| format!("Can't match line: {err}"))?;
+
+            // Stop at the first term that decides the outcome
+            match (self.options.any, is_match) {
+                (true, true) => return Ok(true),
+                (false, false) => return Ok(false),
+                _ => {}
+            }
+        }
+        // Without terms, e.g. with `--bin` only, everything matches
+        Ok(!self.options.any || self.terms.is_empty())
+    }
+
+    /// Find all terms in the columns of a package
+    ///
+    /// Terms restricted to a column are only searched there, the others in the
+    /// whole cache line, like `is_match` does. Overlapping ranges are merged.
+    pub fn highlights(
+        &self,
+        name: &str,
+        version: &str,
+        description: &str,
+    ) -> Result<Highlights, Box<dyn Error>> {
+        let line = format!("{name}\t{version}\t{description}");
+        let version_start = name.len() + 1;
+        let description_start = version_start + version.len() + 1;
+        let columns = [
+            (Field::Name, 0, name.len()),
+            (Field::Version, version_start, version.len()),
+            (Field::Description, description_start, description.len()),
+        ];
+
+        let mut highlights = Highlights::default();
+        for (term, matcher) in self.terms.iter().zip(self.matchers.iter()) {
+            for (field, start, len) in columns {
+                let (haystack, offset) = match term.field {
+                    Some(term_field) if term_field != field => continue,
+                    Some(_) => (&line[start..start + len], start),
+                    // Search the whole line once, with the name column
+                    None if field != Field::Name => continue,
+                    None => (line.as_str(), 0),
+                };
+                let mut found_ranges = vec![];
+                matcher
+                    .find_iter(haystack.as_bytes(), |found| {
+                        // Empty matches, e.g. of `^`, have nothing to highlight
+                        if !found.is_empty() {
+                            found_ranges.push(found.start() + offset..found.end() + offset);
+                        }
+                        true
+                    })
+                    .map_err(|err| format!("Can't match line: {err}"))?;
+
+                // Assign ranges to the columns they overlap with
+                for found in found_ranges {
+                    for (field, start, len) in columns {
+                        let (range_start, range_end) =
+                            (found.start.max(start), found.end.min(start + len));
+                        if range_start < range_end {
+                            highlights
+                                .column_mut(field)
+                                .push(range_start - start..range_end - start);
+                        }
+                    }
+                }
+            }
+        }
+        for field in [Field::Name, Field::Version, Field::Description] {
+            merge_ranges(highlights.column_mut(field));
+        }
+        Ok(highlights)
+    }
+
+    /// Name or name prefix to look up in the name index, if one of the terms
+    /// allows it
+    ///
+    /// With `any`, other terms could match packages the lookup doesn't find.
+    pub(crate) fn name_lookup(&self) -> Option<(&str, bool)> {
+        if self.options.any {
+            return None;
+        }
+        self.terms
+            .iter()
+            .find_map(|term| term.name_lookup(self.options.fixed_strings))
+    }
+}
+
+/// Parse a search term with an optional `name:`, `desc:` or `version:` prefix
+fn parse_query_term(term: &str) -> Result<QueryTerm, String> {
+    let (field, pattern) = match term.split_once(':') {
+        Some(("name", pattern)) => (Some(Field::Name), pattern),
+        Some(("desc" | "description", pattern)) => (Some(Field::Description), pattern),
+        Some(("version", pattern)) => (Some(Field::Version), pattern),
+        _ => (None, term),
+    };
+    // Fall back to a literal search for invalid regexes, for stacked
+    // repetitions like `c++` and for names like `gtk+3`. They are valid, but
+    // can't be meant as a regex.
+    let literal = RegexMatcherBuilder::new().build(pattern).is_err()
+        || ["++", "+*", "*+", "**"]
+            .iter()
+            .any(|repetitions| pattern.contains(repetitions))
+        || is_name_with_plus(pattern);
+    Ok(QueryTerm {
+        field,
+        pattern: pattern.to_string(),
+        literal,
+    })
+}
+
+/// Is the pattern a name with `+` in it, like `gtk+3`?
+///
+/// That's the case if every `+` follows a word character and there are no
+/// other regex metacharacters.
+fn is_name_with_plus(pattern: &str) -> bool {
+    let mut previous: Option<char> = None;
+    for char in pattern.chars() {
+        let after_word =
+            previous.is_some_and(|previous| previous.is_alphanumeric() || previous == '_');
+        if (char == '+' && !after_word) || "\\.^$*?()[]{}|".contains(char) {
+            return false;
+        }
+        previous = Some(char);
+    }
+    pattern.contains('+')
+}
+
+/// The search term matches are sorted by
+///
+/// This is the first one that isn't restricted to versions or descriptions, or
+/// the first one if there are only such terms.
+fn primary_term(terms: &[QueryTerm]) -> Option<&QueryTerm> {
+    terms
+        .iter()
+        .find(|term| matches!(term.field, None | Some(Field::Name)))
+        .or(terms.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_parse_query_term() {
+        init();
+
+        let term = |field, pattern: &str| QueryTerm {
+            field,
+            pattern: pattern.to_string(),
+            literal: false,
+        };
+
+        assert_eq!(parse_query_term("rust"), Ok(term(None, "rust")));
+        assert_eq!(
+            parse_query_term("name:rust"),
+            Ok(term(Some(Field::Name), "rust"))
+        );
+        assert_eq!(
+            parse_query_term("desc:analyzer"),
+            Ok(term(Some(Field::Description), "analyzer"))
+        );
+        assert_eq!(
+            parse_query_term("description:a:b"),
+            Ok(term(Some(Field::Description), "a:b"))
+        );
+        assert_eq!(
+            parse_query_term("version:^1."),
+            Ok(term(Some(Field::Version), "^1."))
+        );
+        assert_eq!(parse_query_term("foo:bar"), Ok(term(None, "foo:bar")));
+        assert_eq!(parse_query_term("^gtk.?3$"), Ok(term(None, "^gtk.?3$")));
+        assert_eq!(
+            parse_query_term("name:(foo"),
+            Ok(QueryTerm {
+                field: Some(Field::Name),
+                pattern: "(foo".to_string(),
+                literal: true,
+            })
+        );
+        assert_eq!(
+            parse_query_term("c++"),
+            Ok(QueryTerm {
+                field: None,
+                pattern: "c++".to_string(),
+                literal: true,
+            })
+        );
+        assert_eq!(
+            parse_query_term("gtk+3"),
+            Ok(QueryTerm {
+                field: None,
+                pattern: "gtk+3".to_string(),
+                literal: true,
+            })
+        );
+        // Other regex syntax next to `+` keeps it a regex
+        assert_eq!(parse_query_term("^gtk+3"), Ok(term(None, "^gtk+3")));
+        assert_eq!(parse_query_term("gtk[0-9]+"), Ok(term(None, "gtk[0-9]+")));
+
+        let query = Query::parse(
+            &["desc:rust", "name:rust-analyzer"],
+            QueryOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            query.primary_term().unwrap(),
+            &term(Some(Field::Name), "rust-analyzer")
+        );
+        let query = Query::parse(&["desc:rust", "version:1"], QueryOptions::default()).unwrap();
+        assert_eq!(
+            query.primary_term().unwrap(),
+            &term(Some(Field::Description), "rust")
+        );
+        assert_eq!(primary_term(&[]), None);
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_highlights() {
+        init();
+
+        let options = QueryOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        let query = Query::parse(&["vim", "desc:editor", "version:^0"], options).unwrap();
+        assert_eq!(
+            query
+                .highlights("neovim", "0.10.3", "Vim text editor, Neovim fork")
+                .unwrap(),
+            Highlights {
+                name: vec![3..6],
+                version: vec![0..1],
+                description: vec![0..3, 9..15, 20..23],
+            }
+        );
+
+        // Unrestricted terms are searched in the whole line, like for matching
+        let query = Query::parse(&["^neo", "m\\t0"], options).unwrap();
+        assert_eq!(
+            query.highlights("neovim", "0.1", "Neovim").unwrap(),
+            Highlights {
+                name: vec![0..3, 5..6],
+                version: vec![0..1],
+                description: vec![],
+            }
+        );
+
+        let query = Query::parse(&["ab", "bc"], options).unwrap();
+        assert_eq!(query.highlights("abc", "", "").unwrap().name, vec![0..3]);
+    }
+}
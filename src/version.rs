@@ -0,0 +1,191 @@
+//! Version constraints and comparison like Nix does it
+
+use std::str::FromStr;
+
+/// Comparison operators of version constraints
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VersionOperator {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+/// Version constraint like `>=3.11`
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionConstraint {
+    pub operator: VersionOperator,
+    pub version: String,
+}
+
+impl VersionConstraint {
+    /// Check if `version` satisfies the constraint, empty versions never do
+    pub fn is_match(&self, version: &str) -> bool {
+        if version.is_empty() {
+            return false;
+        }
+        let ordering = compare_versions(version, &self.version);
+        match self.operator {
+            VersionOperator::Less => ordering.is_lt(),
+            VersionOperator::LessOrEqual => ordering.is_le(),
+            VersionOperator::Equal => ordering.is_eq(),
+            VersionOperator::NotEqual => ordering.is_ne(),
+            VersionOperator::GreaterOrEqual => ordering.is_ge(),
+            VersionOperator::Greater => ordering.is_gt(),
+        }
+    }
+}
+
+impl FromStr for VersionConstraint {
+    type Err = String;
+
+    /// Parse a version constraint like `>=3.11`, see `parse_version_constraint`
+    fn from_str(constraint: &str) -> Result<Self, Self::Err> {
+        parse_version_constraint(constraint)
+    }
+}
+
+/// Parse a version constraint like `>=3.11`, a plain version means `=`
+fn parse_version_constraint(constraint: &str) -> Result<VersionConstraint, String> {
+    let constraint = constraint.trim();
+    let split_at = constraint
+        .find(|character| !"<>=!".contains(character))
+        .unwrap_or(constraint.len());
+    let (operator, version) = constraint.split_at(split_at);
+    let operator = match operator {
+        "<" => VersionOperator::Less,
+        "<=" => VersionOperator::LessOrEqual,
+        "" | "=" | "==" => VersionOperator::Equal,
+        "!=" => VersionOperator::NotEqual,
+        ">=" => VersionOperator::GreaterOrEqual,
+        ">" => VersionOperator::Greater,
+        _ => {
+            return Err(format!(
+                "Unknown operator '{operator}', use one of <, <=, =, !=, >=, >"
+            ))
+        }
+    };
+    let version = version.trim();
+    if version.is_empty() {
+        return Err(format!("Missing version in '{constraint}'"));
+    }
+    Ok(VersionConstraint {
+        operator,
+        version: version.to_string(),
+    })
+}
+
+/// Split a version into components like Nix does
+///
+/// Components are runs of digits or runs of other characters, separated by
+/// dots and dashes, e.g. `1.2pre3` is `1`, `2`, `pre`, `3`.
+fn version_components(version: &str) -> Vec<&str> {
+    let mut components = vec![];
+    let mut rest = version;
+    loop {
+        rest = rest.trim_start_matches(['.', '-']);
+        let Some(first) = rest.chars().next() else {
+            return components;
+        };
+        let end = match first.is_ascii_digit() {
+            true => rest.find(|character: char| !character.is_ascii_digit()),
+            false => rest.find(|character: char| {
+                character.is_ascii_digit() || character == '.' || character == '-'
+            }),
+        }
+        .unwrap_or(rest.len());
+        components.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+}
+
+/// Is the version component `a` lower than `b`, like in Nix's `componentsLT`
+fn component_less(a: &str, b: &str) -> bool {
+    match (a.parse::<u64>().ok(), b.parse::<u64>().ok()) {
+        (Some(a), Some(b)) => a < b,
+        (None, Some(_)) if a.is_empty() => true,
+        _ if a == "pre" && b != "pre" => true,
+        _ if b == "pre" => false,
+        // Assume that `2.3a` < `2.3.1`
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => a < b,
+    }
+}
+
+/// Compare versions like `builtins.compareVersions`
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let a_components = version_components(a);
+    let b_components = version_components(b);
+    for index in 0..a_components.len().max(b_components.len()) {
+        // Missing components count as empty
+        let a_component = a_components.get(index).unwrap_or(&"");
+        let b_component = b_components.get(index).unwrap_or(&"");
+        if component_less(a_component, b_component) {
+            return std::cmp::Ordering::Less;
+        }
+        if component_less(b_component, a_component) {
+            return std::cmp::Ordering::Greater;
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init() {
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        init();
+
+        use std::cmp::Ordering::{Equal, Greater, Less};
+        // Examples from the Nix manual and test suite
+        assert_eq!(compare_versions("1.0", "2.3"), Less);
+        assert_eq!(compare_versions("2.1", "2.3"), Less);
+        assert_eq!(compare_versions("2.3", "2.3"), Equal);
+        assert_eq!(compare_versions("2.5", "2.3"), Greater);
+        assert_eq!(compare_versions("3.1", "2.3"), Greater);
+        assert_eq!(compare_versions("2.3.1", "2.3"), Greater);
+        assert_eq!(compare_versions("2.3.1", "2.3a"), Greater);
+        assert_eq!(compare_versions("2.3pre1", "2.3"), Less);
+        assert_eq!(compare_versions("2.3pre3", "2.3pre12"), Less);
+        assert_eq!(compare_versions("2.3a", "2.3c"), Less);
+        assert_eq!(compare_versions("2.3pre1", "2.3c"), Less);
+        assert_eq!(compare_versions("2.3pre1", "2.3q"), Less);
+        assert_eq!(compare_versions("1.10", "1.9"), Greater);
+        assert_eq!(compare_versions("2.3-1", "2.3.1"), Equal);
+    }
+
+    #[test]
+    fn test_parse_version_constraint() {
+        init();
+
+        let constraint = parse_version_constraint(">=3.11").unwrap();
+        assert_eq!(
+            constraint,
+            VersionConstraint {
+                operator: VersionOperator::GreaterOrEqual,
+                version: "3.11".to_string(),
+            }
+        );
+        assert!(constraint.is_match("3.11.2"));
+        assert!(constraint.is_match("3.12"));
+        assert!(!constraint.is_match("3.9"));
+        assert!(!constraint.is_match(""));
+
+        let constraint = parse_version_constraint("1.2").unwrap();
+        assert_eq!(constraint.operator, VersionOperator::Equal);
+        assert!(constraint.is_match("1.2"));
+        assert!(!constraint.is_match("1.2.0"));
+
+        assert!(parse_version_constraint("=>1").is_err());
+        assert!(parse_version_constraint("<").is_err());
+    }
+}
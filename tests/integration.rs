@@ -272,6 +272,679 @@ nixpkgs.MyTestPackageName    1.0.0  Test package description
         .stdout(predicate::str::diff(desired_output));
 }
 
+#[test]
+fn experimental_output_json() {
+    init();
+
+    let desired_output = r#"[{"name":"MatchMyDescription2","attr_path":"MatchMyDescription2","flake":"nixpkgs","version":"9.8.7","description":"mytestpackageName appears in my description with different capitalization","match_type":"direct"},{"name":"MatchMyDescription1","attr_path":"MatchMyDescription1","flake":"nixpkgs","version":"9.8.7","description":"Also here MyTestPackageName appears in my description","match_type":"direct"},{"name":"MatchMyDescription","attr_path":"MatchMyDescription","flake":"nixpkgs","version":"a.b.c","description":"MyTestPackageName appears in my description","match_type":"exact"}]
+"#;
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("-i=false")
+        .arg("--cache-folder=tests/")
+        .arg("--experimental=true")
+        .arg("--output=json")
+        .arg("MatchMyDescription")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn output_ndjson_flip() {
+    init();
+
+    let desired_output = r#"{"name":"MyTestPackageName2","attr_path":"nixpkgs.MyTestPackageName2","version":"1.0.1","description":"","match_type":"exact"}
+{"name":"MyTestPackageName2","attr_path":"nixos.MyTestPackageName2","version":"1.0.1","description":"","match_type":"exact"}
+"#;
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("--experimental=false")
+        .arg("--output=ndjson")
+        .arg("--flip")
+        .arg("MyTestPackageName2")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn legacy_cache_migration() {
+    init();
+
+    // Create a temporary directory with a cache in the legacy format
+    let temp_dir = TempDir::new().unwrap();
+    let cache_path = temp_dir.path().join("nps.experimental.cache");
+    fs::write(
+        &cache_path,
+        "MyTestPackageName 1.0.0 Test package description\n\
+        MyTestPackageName1 1.1.0 Another test package description",
+    )
+    .unwrap();
+
+    let desired_output = "MyTestPackageName1  1.1.0  Another test package description
+
+MyTestPackageName   1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg(format!("--cache-folder={}", temp_dir.path().display()))
+        .arg("--experimental=true")
+        .arg("--quiet")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    let cache_content = fs::read_to_string(&cache_path).unwrap();
+    assert!(cache_content.starts_with("{\"format\":3,\"source\":\"flake\""));
+    assert!(cache_content.contains("MyTestPackageName1\t1.1.0\tAnother test package description"));
+}
+
+#[test]
+fn stale_cache_warning() {
+    init();
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("--experimental=true")
+        .arg("--max-cache-age=1d")
+        .arg("MyTestPackageName1")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+
+    // The age of the test cache keeps growing, so we skip checking it
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(
+            "MyTestPackageName1  1.1.0  Another test package description\n",
+        ))
+        .stderr(
+            predicate::str::is_match("Cache of nixpkgs is .* old. Refresh with `nps -r`.\n")
+                .unwrap(),
+        );
+}
+
+#[test]
+fn invalid_max_cache_age() {
+    init();
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("--experimental=true")
+        .arg("MyTestPackageName1")
+        .env_clear()
+        .env("NIX_PACKAGE_SEARCH_MAX_CACHE_AGE", "7 years");
+
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Unknown unit"));
+}
+
+#[test]
+fn multiple_flakes_output() {
+    init();
+
+    // Create a temporary directory with caches of two flakes
+    let temp_dir = TempDir::new().unwrap();
+    fs::copy(
+        "tests/nps.experimental.cache",
+        temp_dir.path().join("nps.experimental.cache"),
+    )
+    .unwrap();
+    fs::write(
+        temp_dir
+            .path()
+            .join("nps.flake.github%3Ame%2Fmy-flake.cache"),
+        "{\"format\":3,\"source\":\"flake\",\"flake\":\"github:me/my-flake\",\
+        \"revision\":null,\"created\":1736899200,\"nps_version\":\"0.2.5\"}\n\
+        MyTestPackageName1\t2.0.0\tMy own flavor of a test package\n\
+        MyOtherPackage\t0.1.0\tNot a test package",
+    )
+    .unwrap();
+
+    let desired_output =
+        "github:me/my-flake#MyTestPackageName1  2.0.0  My own flavor of a test package
+nixpkgs#MyTestPackageName1             1.1.0  Another test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg(format!("--cache-folder={}", temp_dir.path().display()))
+        .arg("--flake=nixpkgs,github:me/my-flake")
+        .arg("MyTestPackageName1")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    let desired_output = r#"[{"name":"MyTestPackageName1","attr_path":"MyTestPackageName1","flake":"github:me/my-flake","version":"2.0.0","description":"My own flavor of a test package","match_type":"exact"}]
+"#;
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg(format!("--cache-folder={}", temp_dir.path().display()))
+        .arg("--output=json")
+        .arg("MyTestPackageName1")
+        .arg("-dddd")
+        .env_clear() // remove env vars
+        .env("NIX_PACKAGE_SEARCH_FLAKES", "github:me/my-flake");
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn merged_output() {
+    init();
+
+    // Create a temporary directory with caches of channels and a flake
+    let temp_dir = TempDir::new().unwrap();
+    fs::copy("tests/nps.cache", temp_dir.path().join("nps.cache")).unwrap();
+    fs::write(
+        temp_dir
+            .path()
+            .join("nps.flake.github%3Ame%2Fmy-flake.cache"),
+        "{\"format\":3,\"source\":\"flake\",\"flake\":\"github:me/my-flake\",\
+        \"revision\":null,\"created\":1736899200,\"nps_version\":\"0.2.5\"}\n\
+        MyTestPackageName1\t2.0.0\tMy own flavor of a test package\n\
+        MyTestPackageName4\t0.1.0\tOnly in my flake",
+    )
+    .unwrap();
+
+    let desired_output = "MyTestPackageName4  0.1.0  [github:me/my-flake]                                Only in my flake
+MyTestPackageName1  1.1.0  [channel:nixpkgs,channel:nixos,github:me/my-flake]  Another test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg(format!("--cache-folder={}", temp_dir.path().display()))
+        .arg("--merge")
+        .arg("--flake=github:me/my-flake")
+        .arg("MyTestPackageName[14]")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn fuzzy_output() {
+    init();
+
+    let desired_output = "\
+mytestpackageName3  3.2.1  More test package description, now with MyTestPackageName
+MyTestPackageName3  1.2.1  More test package description
+MyTestPackageName2  1.0.1  
+MyTestPackageName1  1.1.0  Another test package description
+MyTestPackageName   1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--fuzzy")
+        .arg("MyTestPakcageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn no_matches_suggestions() {
+    init();
+
+    let desired_output = "\
+No matches for \"MyTestPackageNme\", did you mean
+  MyTestPackageName
+  MyTestPackageName1
+  MyTestPackageName2
+  MyTestPackageName3
+  mytestpackageName3
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("MyTestPackageNme")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::diff(desired_output));
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("-q")
+        .arg("MyTestPackageNme")
+        .env_clear(); // remove env vars
+    cmd.assert().failure().stderr(predicate::str::is_empty());
+}
+
+#[test]
+fn multiple_terms_output() {
+    init();
+
+    let desired_output = "\
+MatchMyDescription2  9.8.7  mytestpackageName appears in my description with different capitalization
+MatchMyDescription1  9.8.7  Also here MyTestPackageName appears in my description
+MatchMyDescription   a.b.c  MyTestPackageName appears in my description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("MatchMy")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    let desired_output = "\
+MyTestPackageName2     1.0.1  
+
+DoNotMatchThisPackage  0.0.0  I have a description as well
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--any")
+        .arg("DoNotMatch")
+        .arg("MyTestPackageName2")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn field_scoped_output() {
+    init();
+
+    let desired_output = "\
+MyTestPackageName3  1.2.1  More test package description
+MyTestPackageName1  1.1.0  Another test package description
+MyTestPackageName   1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg(r"version:^1\.")
+        .arg("desc:package")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn fixed_strings_output() {
+    init();
+
+    let desired_output = "\
+MatchMyDescription  a.b.c  MyTestPackageName appears in my description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("-F")
+        .arg("a.b.c")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    // Invalid regexes are searched for literally, stdout is kept for results
+    let desired_notice = "\
+\"(MyTestPackageName\" doesn't look like a regex, searching for it literally.
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("(MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .failure()
+        .stdout(predicate::str::is_empty())
+        .stderr(predicate::str::contains(desired_notice));
+}
+
+#[test]
+fn action_dry_run() {
+    init();
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--shell")
+        .arg("--dry-run")
+        .arg("MyTestPackageName")
+        .arg("MyTestPackageName2")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert().success().stdout(predicate::str::diff(
+        "nix shell nixpkgs#MyTestPackageName nixpkgs#MyTestPackageName2\n",
+    ));
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=false")
+        .arg("--install")
+        .arg("--dry-run")
+        .arg("MyTestPackageName1")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert().success().stdout(predicate::str::diff(
+        "nix-env -iA nixpkgs.MyTestPackageName1\n",
+    ));
+
+    // No package named like this
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--run")
+        .arg("--dry-run")
+        .arg("MyTestPackage")
+        .env_clear(); // remove env vars
+    cmd.assert().failure().stdout(predicate::str::is_empty());
+}
+
+#[test]
+fn bin_output() {
+    init();
+
+    let desired_output = "\
+MyTestPackageName3  1.2.1  More test package description
+MyTestPackageName2  1.0.1  
+
+MyTestPackageName1  1.1.0  Another test package description
+
+MyTestPackageName   1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--bin=mytest")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    // Search terms narrow packages down
+    let desired_output = "\
+nixpkgs.MyTestPackageName  1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=false")
+        .arg("--bin=mytest")
+        .arg("nixpkgs")
+        .arg("desc:^Test")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--bin=nothing")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert().failure().stdout(predicate::str::is_empty());
+}
+
+#[test]
+fn info_output() {
+    init();
+
+    let desired_output = "\
+nixpkgs#MyTestPackageName2
+  version:      1.0.1
+  main program: mytest-cli
+  licenses:     unfree
+  platforms:    x86_64-linux, x86_64-darwin
+  broken:       no
+  unfree:       yes
+  insecure:     no
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--info=MyTestPackageName2")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    // Channel packages can be named without channel
+    let desired_output = "\
+nixpkgs.MyTestPackageName1
+  version:      1.1.0
+  description:  Another test package description
+  broken:       no
+  unfree:       no
+  insecure:     no
+
+nixos.MyTestPackageName1
+  version:      1.1.0
+  description:  Another test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=false")
+        .arg("--info=MyTestPackageName1")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--info=NoSuchPackage")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert().failure().stdout(predicate::str::is_empty());
+}
+
+#[test]
+fn filtered_output() {
+    init();
+
+    let desired_output = "\
+mytestpackageName3  3.2.1  More test package description, now with MyTestPackageName
+MyTestPackageName1  1.1.0  Another test package description
+
+MyTestPackageName   1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--license=mit,apache-2.0")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    // Padding only accounts for visible packages
+    let desired_output = "\
+MyTestPackageName1  1.1.0  Another test package description
+
+MyTestPackageName   1.0.0  Test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--platform=x86_64-linux")
+        .arg("--no-unfree")
+        .arg("--no-broken")
+        .arg("--no-insecure")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+}
+
+#[test]
+fn version_constraint_output() {
+    init();
+
+    let desired_output = "\
+MyTestPackageName2  1.0.1  
+MyTestPackageName1  1.1.0  Another test package description
+MyTestPackageName3  1.2.1  More test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--version-constraint=>1.0.0,<3")
+        .arg("--sort=version")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--version-constraint=>>1")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert().failure();
+}
+
+#[test]
+fn changes_output() {
+    init();
+
+    let desired_output = r#"{"flake":"nixpkgs","previous_created":1736294400,"created":1736899200,"added":[{"name":"MatchMyDescription2","new_version":"9.8.7"}],"removed":[{"name":"OldPackage","old_version":"0.1.0"}],"updated":[{"name":"MyTestPackageName1","old_version":"1.0.0","new_version":"1.1.0"}]}
+"#;
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=true")
+        .arg("--changes")
+        .arg("--output=ndjson")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    // There is no previous channel cache
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--cache-folder=tests/")
+        .arg("-e=false")
+        .arg("--changes")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert().failure().stdout(predicate::str::is_empty());
+}
+
+#[test]
+fn system_cache_output() {
+    init();
+
+    // Nothing in the own cache folder, the system cache is used instead
+    let cache_folder = TempDir::new().unwrap();
+    let desired_output = "MyTestPackageName1  1.1.0  Another test package description
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg(format!("--cache-folder={}", cache_folder.path().display()))
+        .arg("--system-cache-folder=tests/")
+        .arg("-e=true")
+        .arg("name:^MyTestPackageName1$")
+        .arg("-dddd")
+        .env_clear(); // remove env vars
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+    assert_eq!(fs::read_dir(cache_folder.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn config_file_output() {
+    init();
+
+    let config_home = TempDir::new().unwrap();
+    fs::create_dir(config_home.path().join("nps")).unwrap();
+    fs::write(
+        config_home.path().join("nps").join("config.toml"),
+        r#"
+cache_folder_absolute_path = "tests/"
+columns = "none"
+
+[profile.flakes]
+experimental = true
+"#,
+    )
+    .unwrap();
+
+    let desired_output = "MyTestPackageName
+";
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--profile=flakes")
+        .arg("--quiet")
+        .arg("name:^MyTestPackageName$")
+        .arg("-dddd")
+        .env_clear() // remove env vars
+        .env("XDG_CONFIG_HOME", config_home.path());
+    cmd.assert()
+        .success()
+        .stdout(predicate::str::diff(desired_output));
+
+    // Command line options override the config file
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--print-config")
+        .arg("--profile=flakes")
+        .arg("--columns=version")
+        .arg("-dddd")
+        .env_clear() // remove env vars
+        .env("XDG_CONFIG_HOME", config_home.path());
+    cmd.assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(r#"cache_folder_absolute_path = "tests/" +# config file"#)
+                .unwrap(),
+        )
+        .stdout(predicate::str::is_match(r#"columns = "version" +# command line"#).unwrap())
+        .stdout(predicate::str::is_match("experimental = true +# profile flakes").unwrap())
+        .stdout(predicate::str::contains("# max_cache_age is not set"));
+
+    let mut cmd = Command::cargo_bin("nps").unwrap();
+    cmd.arg("--profile=unknown")
+        .arg("MyTestPackageName")
+        .arg("-dddd")
+        .env_clear() // remove env vars
+        .env("XDG_CONFIG_HOME", config_home.path());
+    cmd.assert()
+        .failure()
+        .stderr(predicate::str::contains("Can't find profile `unknown`"));
+}
+
 // The following tests are not run by default. Use
 //
 // cargo test -- --ignored
@@ -309,7 +982,7 @@ fn cache_creation() {
         let output = cmd.assert().success();
 
         let cache_content = fs::read_to_string(temp_path.join("nps.cache")).unwrap();
-        let re_vim = Regex::new("vim .*popular clone of the VI editor").unwrap();
+        let re_vim = Regex::new(r"vim\s.*popular clone of the VI editor").unwrap();
         assert!(re_vim.is_match(&cache_content));
 
         let re_done = Regex::new("Done. Cached info of").unwrap();
@@ -355,7 +1028,7 @@ fn experimental_cache_creation() {
         let output = cmd.assert().success();
 
         let cache_content = fs::read_to_string(temp_path.join("nps.experimental.cache")).unwrap();
-        let re = Regex::new("vim .*popular clone of the VI editor").unwrap();
+        let re = Regex::new(r"vim\s.*popular clone of the VI editor").unwrap();
         assert!(re.is_match(&cache_content));
 
         let re_done = Regex::new("Done. Cached info of").unwrap();